@@ -0,0 +1,337 @@
+//command line front end for the database, used both for one-shot subcommands and the REPL
+//every command is a thin wrapper around one of the translated database functions
+
+use crate::{
+    add_user, create_checked_user, find_free_slot, find_user_by_id, find_user_by_username,
+    init_database, print_database, print_user, u8_to_string_no_nullt, update_database_daily,
+    update_password, update_username, user_login, UserDatabase, UserStruct, MAX_SESSION_TOKEN_LEN,
+};
+use std::fs;
+use std::io::{self, BufRead, Write};
+
+pub const USAGE: &str = "\
+commands:
+  add <username> <email> <password>   create a user
+  login <username>                    reset the user's inactivity count
+  rename <username> <new_username>    change a username
+  passwd <username> <new_password>    change a password
+  find --id <id> | --name <username>  print a user
+  tick [days]                         run the daily update (default 1 day)
+  dump [file]                         print the database, or save it to file
+  load <file>                         replace the database with a saved one
+  help                                show this message
+  quit                                leave the REPL";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Add {
+        username: String,
+        email: String,
+        password: String,
+    },
+    Login {
+        username: String,
+    },
+    Rename {
+        username: String,
+        new_username: String,
+    },
+    Passwd {
+        username: String,
+        password: String,
+    },
+    FindById(i32),
+    FindByName(String),
+    Tick(u32),
+    Dump(Option<String>),
+    Load(String),
+    Help,
+    Quit,
+}
+
+//splits a command line on whitespace, an empty line gives no tokens
+//'#' is rejected rather than cut off at, only scenario files have (whole line) comments
+pub fn tokenize(line: &str) -> Result<Vec<&str>, String> {
+    if line.contains('#') {
+        return Err("'#' is not allowed in commands".to_string());
+    }
+    return Ok(line.split_whitespace().collect());
+}
+
+pub fn parse_command(tokens: &[&str]) -> Result<Command, String> {
+    let (name, args) = match tokens.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Err("empty command".to_string()),
+    };
+    let arity = |n: usize| -> Result<(), String> {
+        if args.len() != n {
+            return Err(format!(
+                "{} takes {} argument(s), got {}",
+                name,
+                n,
+                args.len()
+            ));
+        }
+        return Ok(());
+    };
+    let command = match name {
+        "add" => {
+            arity(3)?;
+            Command::Add {
+                username: args[0].to_string(),
+                email: args[1].to_string(),
+                password: args[2].to_string(),
+            }
+        }
+        "login" => {
+            arity(1)?;
+            Command::Login {
+                username: args[0].to_string(),
+            }
+        }
+        "rename" => {
+            arity(2)?;
+            Command::Rename {
+                username: args[0].to_string(),
+                new_username: args[1].to_string(),
+            }
+        }
+        "passwd" => {
+            arity(2)?;
+            Command::Passwd {
+                username: args[0].to_string(),
+                password: args[1].to_string(),
+            }
+        }
+        "find" => {
+            arity(2)?;
+            match args[0] {
+                "--id" => match args[1].parse::<i32>() {
+                    Ok(id) => Command::FindById(id),
+                    Err(_) => return Err(format!("invalid id: {}", args[1])),
+                },
+                "--name" => Command::FindByName(args[1].to_string()),
+                other => return Err(format!("find expects --id or --name, got {}", other)),
+            }
+        }
+        "tick" => match args {
+            [] => Command::Tick(1),
+            [days] => match days.parse::<u32>() {
+                Ok(days) => Command::Tick(days),
+                Err(_) => return Err(format!("invalid number of days: {}", days)),
+            },
+            _ => return Err("tick takes at most 1 argument".to_string()),
+        },
+        "dump" => match args {
+            [] => Command::Dump(None),
+            [file] => Command::Dump(Some(file.to_string())),
+            _ => return Err("dump takes at most 1 argument".to_string()),
+        },
+        "load" => {
+            arity(1)?;
+            Command::Load(args[0].to_string())
+        }
+        "help" => Command::Help,
+        "quit" | "exit" => Command::Quit,
+        other => return Err(format!("unknown command: {} (try help)", other)),
+    };
+    return Ok(command);
+}
+
+pub fn execute(db: &mut UserDatabase, command: &Command) -> Result<(), String> {
+    match command {
+        Command::Add {
+            username,
+            email,
+            password,
+        } => {
+            let user =
+                create_checked_user(username, email, 0, password).map_err(|e| e.to_string())?;
+            add_user(db, user).map_err(|e| e.to_string())?;
+            println!("added {}", username);
+        }
+        Command::Login { username } => {
+            user_login(db, username).map_err(|e| e.to_string())?;
+            println!("{} logged in", username);
+        }
+        Command::Rename {
+            username,
+            new_username,
+        } => {
+            update_username(db, username, new_username).map_err(|e| e.to_string())?;
+            println!("renamed {} to {}", username, new_username);
+        }
+        Command::Passwd { username, password } => {
+            update_password(db, username, password).map_err(|e| e.to_string())?;
+            println!("password updated for {}", username);
+        }
+        Command::FindById(id) => match find_user_by_id(db, *id) {
+            Some(user) => print_user(user),
+            None => return Err(format!("no user with id {}", id)),
+        },
+        Command::FindByName(username) => match find_user_by_username(db, username) {
+            Some(user) => print_user(user),
+            None => return Err(format!("no user named {}", username)),
+        },
+        Command::Tick(days) => {
+            for _ in 0..*days {
+                update_database_daily(db);
+            }
+            println!("{} day(s) passed, {} user(s) left", days, db.count);
+        }
+        Command::Dump(None) => print_database(db),
+        Command::Dump(Some(path)) => {
+            save_database(db, path)?;
+            println!("saved {} user(s) to {}", db.count, path);
+        }
+        Command::Load(path) => {
+            *db = load_database(path)?;
+            println!("loaded {} user(s) from {}", db.count, path);
+        }
+        Command::Help => println!("{}", USAGE),
+        Command::Quit => {}
+    }
+    return Ok(());
+}
+
+//dump files are one user per line:
+//user_id <TAB> username <TAB> email <TAB> password <TAB> inactivity_count <TAB> is_active <TAB> session_token
+//with \t, \n and \ escaped inside the text fields
+fn escape(field: &str) -> String {
+    let mut out = String::new();
+    for c in field.chars() {
+        match c {
+            '\\' => out.push_str("\\\\"),
+            '\t' => out.push_str("\\t"),
+            '\n' => out.push_str("\\n"),
+            _ => out.push(c),
+        }
+    }
+    return out;
+}
+
+fn unescape(field: &str) -> Result<String, String> {
+    let mut out = String::new();
+    let mut chars = field.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => out.push('\\'),
+            Some('t') => out.push('\t'),
+            Some('n') => out.push('\n'),
+            _ => return Err(format!("bad escape in {:?}", field)),
+        }
+    }
+    return Ok(out);
+}
+
+pub fn dump_database(db: &UserDatabase) -> String {
+    let mut out = String::new();
+    for user in (*db).users.iter().flatten() {
+        out.push_str(&format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            user.user_id,
+            escape(&u8_to_string_no_nullt(&user.username)),
+            escape(&u8_to_string_no_nullt(&user.email)),
+            escape(&u8_to_string_no_nullt(&user.password)),
+            user.inactivity_count,
+            user.is_active,
+            escape(&u8_to_string_no_nullt(&user.session_token)),
+        ));
+    }
+    return out;
+}
+
+fn parse_user(line: &str) -> Result<Box<UserStruct>, String> {
+    let fields: Vec<&str> = line.split('\t').collect();
+    if fields.len() != 7 {
+        return Err(format!("expected 7 fields, got {}", fields.len()));
+    }
+    let number = |i: usize| -> Result<i32, String> {
+        return fields[i]
+            .parse::<i32>()
+            .map_err(|_| format!("invalid number {:?}", fields[i]));
+    };
+    let mut user = create_checked_user(
+        &unescape(fields[1])?,
+        &unescape(fields[2])?,
+        number(0)?,
+        &unescape(fields[3])?,
+    )
+    .map_err(|e| e.to_string())?;
+    user.inactivity_count = number(4)?;
+    user.is_active = number(5)?;
+    let token = unescape(fields[6])?;
+    if token.len() > MAX_SESSION_TOKEN_LEN - 1 {
+        return Err(format!(
+            "session token is too long (max {} bytes)",
+            MAX_SESSION_TOKEN_LEN - 1
+        ));
+    }
+    user.session_token[..token.len()].copy_from_slice(token.as_bytes());
+    return Ok(user);
+}
+
+pub fn undump_database(text: &str) -> Result<UserDatabase, String> {
+    let mut db = init_database();
+    for (i, line) in text.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let user = parse_user(line).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        let user_id = user.user_id;
+        if find_user_by_id(&db, user_id).is_some() {
+            return Err(format!("line {}: duplicate user id {}", i + 1, user_id));
+        }
+        //add_user hands out its own id, the saved one is put back into the slot it used afterwards
+        let index = find_free_slot(&db);
+        add_user(&mut db, user).map_err(|e| format!("line {}: {}", i + 1, e))?;
+        if let Some(Some(_user)) = index.map(|i| &mut db.users[i]) {
+            _user.user_id = user_id;
+        }
+    }
+    return Ok(db);
+}
+
+pub fn save_database(db: &UserDatabase, path: &str) -> Result<(), String> {
+    return fs::write(path, dump_database(db)).map_err(|e| format!("cannot write {}: {}", path, e));
+}
+
+pub fn load_database(path: &str) -> Result<UserDatabase, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    return undump_database(&text).map_err(|e| format!("{}: {}", path, e));
+}
+
+pub fn repl(db: &mut UserDatabase) {
+    println!("{}", USAGE);
+    let stdin = io::stdin();
+    loop {
+        print!("db> ");
+        let _ = io::stdout().flush();
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) | Err(_) => break, //end of input
+            Ok(_) => {}
+        }
+        let tokens = match tokenize(&line) {
+            Ok(tokens) if tokens.is_empty() => continue,
+            Ok(tokens) => tokens,
+            Err(e) => {
+                println!("error: {}", e);
+                continue;
+            }
+        };
+        match parse_command(&tokens) {
+            Ok(Command::Quit) => break,
+            Ok(command) => {
+                if let Err(e) = execute(db, &command) {
+                    println!("error: {}", e);
+                }
+            }
+            Err(e) => println!("error: {}", e),
+        }
+    }
+}
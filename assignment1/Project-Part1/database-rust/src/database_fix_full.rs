@@ -1,6 +1,9 @@
 use std::env;
 use std::ptr::{copy, null};
 
+mod cli;
+mod scenario;
+
 #[derive(Debug)]
 enum PayloadType {
     OUT_OF_BOUNDS_PAYLOAD,
@@ -8,6 +11,26 @@ enum PayloadType {
     USE_AFTER_FREE_PAYLOAD,
 }
 
+impl PayloadType {
+    //the payloads are scenario files (see scenario.rs), built into the binary so payload numbers still work
+    fn scenario(&self) -> (&'static str, &'static str) {
+        return match self {
+            PayloadType::OUT_OF_BOUNDS_PAYLOAD => (
+                "OUT_OF_BOUNDS_PAYLOAD",
+                include_str!("../scenarios/out_of_bounds.scenario"),
+            ),
+            PayloadType::USE_AFTER_FREE_PAYLOAD => (
+                "USE_AFTER_FREE_PAYLOAD",
+                include_str!("../scenarios/use_after_free.scenario"),
+            ),
+            PayloadType::DOUBLE_FREE_PAYLOAD => (
+                "DOUBLE_FREE_PAYLOAD",
+                include_str!("../scenarios/double_free.scenario"),
+            ),
+        };
+    }
+}
+
 //====================================Dont change these definitions==========================
 const MAX_USERS: usize = 100;
 const MAX_NAME_LEN: usize = 50;
@@ -40,6 +63,43 @@ pub struct UserDatabase {
 
 //Complete the body of the program here.
 
+//every operation that can fail returns one of these instead of panicking or silently doing nothing
+#[derive(Debug, Clone, PartialEq)]
+pub enum DbError {
+    Full,                                            //no free slot left in users
+    NotFound,                                        //no user with that username/id
+    DuplicateUsername,                               //username already taken
+    FieldTooLong { field: &'static str, max: usize }, //input would not fit in the fixed size buffer
+    InvalidInput,                                    //empty or contains a NUL byte
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Full => write!(f, "database is full ({} users)", MAX_USERS),
+            DbError::NotFound => write!(f, "user not found"),
+            DbError::DuplicateUsername => write!(f, "username already exists"),
+            DbError::FieldTooLong { field, max } => {
+                write!(f, "{} is too long (max {} bytes)", field, max)
+            }
+            DbError::InvalidInput => write!(f, "invalid input"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+//checks that src fits in a buffer of size n (leaving room for the null terminator)
+fn check_field(field: &'static str, src: &str, n: usize) -> Result<(), DbError> {
+    if src.is_empty() || src.as_bytes().contains(&0) {
+        return Err(DbError::InvalidInput);
+    }
+    if src.len() > n - 1 {
+        return Err(DbError::FieldTooLong { field, max: n - 1 });
+    }
+    return Ok(());
+}
+
 fn init_database() -> UserDatabase {
     let db = UserDatabase {
         users: std::array::from_fn(|_| None), //creates MAX_USERS number of None values and stores in users array
@@ -52,12 +112,28 @@ fn init_database() -> UserDatabase {
 
 //&mut: can modify the value it points to
 //mut: can modify the parameter
-fn add_user(db: &mut UserDatabase, mut user: Box<UserStruct>) {
-    (*user).user_id = (*db).count + 1;
-    //indexing arrays in rust always requires usize indeces
-    let index: usize = (*db).count as usize;
+fn add_user(db: &mut UserDatabase, mut user: Box<UserStruct>) -> Result<(), DbError> {
+    if (*db).count as usize >= MAX_USERS {
+        return Err(DbError::Full);
+    }
+    if find_user_by_username(db, &u8_to_string_no_nullt(&user.username)).is_some() {
+        return Err(DbError::DuplicateUsername);
+    }
+    //one past the highest id in use, count + 1 would hand out an id again once someone was removed
+    (*user).user_id = (*db).users.iter().flatten().map(|u| u.user_id).max().unwrap_or(0) + 1;
+    //removed users leave holes, so the next user goes into the first empty slot rather than at count
+    //(writing at count would overwrite whoever lives there)
+    let index: usize = match find_free_slot(db) {
+        Some(index) => index,
+        None => return Err(DbError::Full),
+    };
     (*db).users[index] = Some(user);
     (*db).count += 1;
+    return Ok(());
+}
+
+fn find_free_slot(db: &UserDatabase) -> Option<usize> {
+    return (*db).users.iter().position(|user| user.is_none());
 }
 
 //dont need to manually free
@@ -100,6 +176,19 @@ fn create_user(username: &str, email: &str, user_id: i32, password: &str) -> Box
     return Box::new(user); //Box is like a malloc pointer, with auto free, auto dereference...
 }
 
+//create_user with the same checks as the updates, so nothing is cut off to fit the buffers
+fn create_checked_user(
+    username: &str,
+    email: &str,
+    user_id: i32,
+    password: &str,
+) -> Result<Box<UserStruct>, DbError> {
+    check_field("username", username, MAX_NAME_LEN)?;
+    check_field("email", email, MAX_EMAIL_LEN)?;
+    check_field("password", password, MAX_PASSWORD_LEN)?;
+    return Ok(create_user(username, email, user_id, password));
+}
+
 fn find_user_by_id(db: &UserDatabase, user_id: i32) -> Option<&Box<UserStruct>> {
     //db = &UserDatabase
     //*db = UserDatabase
@@ -127,7 +216,8 @@ fn cleanup_db(db: &mut UserDatabase) {
 
 fn update_database_daily(db: &mut UserDatabase) {
     let mut users_removed = 0;
-    for i in 0..(*db).count as usize {
+    //walk every slot, users are not packed below count once someone has been removed
+    for i in 0..MAX_USERS {
         //copy user at index i to _user if it it not None (borrow)
         //ref: creates a reference - primarily used in pattern matching, &mut used everuwhere else pretty much
         if let Some(ref mut _user) = (*db).users[i] {
@@ -146,20 +236,31 @@ fn update_database_daily(db: &mut UserDatabase) {
     (*db).count -= users_removed;
 }
 
-fn update_username(db: &mut UserDatabase, username: &str, new_username: &str) {
+fn update_username(db: &mut UserDatabase, username: &str, new_username: &str) -> Result<(), DbError> {
+    check_field("username", new_username, MAX_NAME_LEN)?;
+    if let Some(other) = find_user_by_username(db, new_username) {
+        //renaming to the same name (or a different casing of it) is fine
+        if !u8_to_string_no_nullt(&other.username).eq_ignore_ascii_case(username) {
+            return Err(DbError::DuplicateUsername);
+        }
+    }
     let user: Option<&mut Box<UserStruct>> = find_user_by_username_mut(db, username);
     if let Some(_user) = user {
         //println!("OLD USERNAME: {:?}", _user.username);
         copy_string(&mut _user.username, new_username, MAX_NAME_LEN - 1);
         //println!("NEW USERNAME: {:?}", _user.username);
+        return Ok(());
     }
+    return Err(DbError::NotFound);
 }
 
-fn user_login(db: &mut UserDatabase, username: &str) {
+fn user_login(db: &mut UserDatabase, username: &str) -> Result<(), DbError> {
     let user: Option<&mut Box<UserStruct>> = find_user_by_username_mut(db, username);
     if let Some(_user) = user {
         _user.inactivity_count = 0;
+        return Ok(());
     }
+    return Err(DbError::NotFound);
 }
 
 fn get_nullt_index_from_u8(bytes: &[u8]) -> usize {
@@ -182,13 +283,16 @@ fn get_password(db: &UserDatabase, username: &str) -> Option<String> {
     return Some(u8_to_string_no_nullt(&user.password));
 }
 
-fn update_password(db: &mut UserDatabase, username: &str, password: &str) {
+fn update_password(db: &mut UserDatabase, username: &str, password: &str) -> Result<(), DbError> {
+    check_field("password", password, MAX_PASSWORD_LEN)?;
     let mut user: Option<&mut Box<UserStruct>> = find_user_by_username_mut(db, username);
     if let Some(ref mut _user) = user {
         //println!("OLD PASSWORD: {:?}", u8_to_string_no_nullt(&_user.password));
-        copy_string(&mut _user.password, password, password.len());
+        copy_string(&mut _user.password, password, MAX_PASSWORD_LEN - 1);
         //println!("NEW PASSWORD: {:?}", u8_to_string_no_nullt(&_user.password));
+        return Ok(());
     }
+    return Err(DbError::NotFound);
 }
 
 fn print_user(user: &Box<UserStruct>) {
@@ -266,104 +370,135 @@ for i in 0..(*db).count as usize {
 }
 */
 
-fn main() {
-    let args: Vec<String> = env::args().collect(); //arguments from command line
+//payload numbers from the assignment: 0, 1 and 2
+fn parse_payload(arg: &str) -> Option<PayloadType> {
+    return match arg.parse::<i32>() {
+        Ok(0) => Some(PayloadType::OUT_OF_BOUNDS_PAYLOAD),
+        Ok(1) => Some(PayloadType::USE_AFTER_FREE_PAYLOAD),
+        Ok(2) => Some(PayloadType::DOUBLE_FREE_PAYLOAD),
+        _ => None,
+    };
+}
 
-    if args.len() > 2 {
-        println!("Usage: {:?} <payload_type>", args[0]);
-        println!("OUT_OF_BOUNDS_PAYLOAD");
-        println!("DOUBLE_FREE_PAYLOAD");
-        println!("USE_AFTER_FREE_PAYLOAD");
-        return;
-    }
+fn print_usage(program: &str) {
+    println!("Usage: {} <payload_num>", program);
+    println!("  0 OUT_OF_BOUNDS_PAYLOAD");
+    println!("  1 USE_AFTER_FREE_PAYLOAD");
+    println!("  2 DOUBLE_FREE_PAYLOAD");
+    println!("   or: {} [--db <file>] [repl]            (interactive, the default)", program);
+    println!("   or: {} [--db <file>] <command> [args...]", program);
+    println!("   or: {} scenario <file>...            (run scenario files, see scenarios/)", program);
+    println!("--db loads the database from file first and saves it back afterwards\n");
+    println!("{}", cli::USAGE);
+}
 
-    let payload_num = match args[1].parse::<i32>() {
-        //returns Result<T, error>
-        Ok(num) => num, //if it succeeds, store it in num
-        Err(_) => {
-            println!("Invalid payload type: {}", args[1]);
-            return;
+fn main() {
+    let mut args: Vec<String> = env::args().collect(); //arguments from command line
+    let program = args.remove(0);
+
+    let db_path = match args.iter().position(|a| a == "--db") {
+        Some(i) if i + 1 < args.len() => {
+            let path = args.remove(i + 1);
+            args.remove(i);
+            Some(path)
+        }
+        Some(_) => {
+            print_usage(&program);
+            std::process::exit(2);
         }
+        None => None,
     };
 
-    let payload_type = match payload_num {
-        0 => PayloadType::OUT_OF_BOUNDS_PAYLOAD,
-        1 => PayloadType::USE_AFTER_FREE_PAYLOAD,
-        2 => PayloadType::DOUBLE_FREE_PAYLOAD,
-        _ => {
-            println!("Invalid payload num");
+    if db_path.is_none() && args.len() == 1 {
+        if let Some(payload_type) = parse_payload(&args[0]) {
+            run_payload(payload_type);
             return;
         }
-    };
+    }
 
-    let payload_name = match payload_type {
-        PayloadType::OUT_OF_BOUNDS_PAYLOAD => "OUT_OF_BOUNDS_PAYLOAD",
-        PayloadType::USE_AFTER_FREE_PAYLOAD => "USE_AFTER_FREE_PAYLOAD",
-        PayloadType::DOUBLE_FREE_PAYLOAD => "DOUBLE_FREE_PAYLOAD",
+    let mut db = match &db_path {
+        Some(path) if std::path::Path::new(path).exists() => match cli::load_database(path) {
+            Ok(db) => db,
+            Err(e) => {
+                println!("error: {}", e);
+                std::process::exit(1);
+            }
+        },
+        _ => init_database(),
     };
 
-    println!("Running test payload {:?}", payload_name);
-
-    let mut db = init_database();
-    let mut mallory = create_user("Mallory", "mallory@nus.edu.sg", 0, "malloryisnotevil");
-    add_user(&mut db, mallory.clone());
-    let alice = create_user("Alice", "alice@nus.edu.sg", 1, "aliceinthewonderland");
-    add_user(&mut db, alice);
-    let bob = create_user("Bob", "bob@nus.edu.sg", 2, "bobthebuilder");
-    add_user(&mut db, bob);
-    let eve = create_user("Eve", "eve@nus.edu.sg", 3, "eve4ever");
-    add_user(&mut db, eve);
-
-    match payload_type {
-        PayloadType::OUT_OF_BOUNDS_PAYLOAD => {
-            let long_username = "C".repeat(99); //string with 99 Cs
-            update_username(&mut db, "Mallory", &long_username);
-        }
-        PayloadType::USE_AFTER_FREE_PAYLOAD => {
-            for i in 0..INACTIVITY_THRESHOLD + 2 {
-                println!("----------------------Day {}: User login and database update-----------------------", i + 1);
-                user_login(&mut db, "Alice");
-                user_login(&mut db, "Bob");
-                update_database_daily(&mut db);
-                println!();
+    let tokens: Vec<&str> = args.iter().map(|a| a.as_str()).collect();
+    let mut failed = false;
+    match tokens.as_slice() {
+        [] | ["repl"] => cli::repl(&mut db),
+        ["scenario", files @ ..] if !files.is_empty() => {
+            for file in files {
+                match scenario::run_file(file) {
+                    Ok(()) => println!("{}: ok", file),
+                    Err(e) => {
+                        println!("{}", e);
+                        failed = true;
+                    }
+                }
             }
         }
-        PayloadType::DOUBLE_FREE_PAYLOAD => {
-            println!("--------------Starting a sprint of the database update and user login-------------");
-            for _ in 0..INACTIVITY_THRESHOLD + 2 {
-                user_login(&mut db, "Alice");
-                user_login(&mut db, "Bob");
-                update_database_daily(&mut db);
+        ["-h"] | ["--help"] => {
+            print_usage(&program);
+            return;
+        }
+        _ => match cli::parse_command(&tokens) {
+            Ok(command) => {
+                if let Err(e) = cli::execute(&mut db, &command) {
+                    println!("error: {}", e);
+                    failed = true;
+                }
+            }
+            Err(e) => {
+                println!("error: {}\n", e);
+                print_usage(&program);
+                std::process::exit(2);
             }
+        },
+    }
 
-            println!("--------------Database update and user login sprint finished----------------------");
-            let charlie = create_user(
-                "Charlie",
-                "charlie@nus.edu.sg",
-                3,
-                "charlieandthechocolatefactory",
-            );
-            add_user(&mut db, charlie);
-            let bruce = create_user("Bruce", "bruce@nus.edu.sg", 4, "iambatman");
-            add_user(&mut db, bruce);
-            let joker = create_user("Joker", "joker@nus.edu.sg", 5, "whysoserious");
-            add_user(&mut db, joker);
-            println!("Mallory wants to login and update her password");
-            update_password(&mut db, "Mallory", "Malloryiswatchingyou");
-            println!("Eve wants to get her password => ");
-            let password = get_password(&db, "Eve");
-            println!("Eve's password is: {:?}", password);
+    if let Some(path) = &db_path {
+        if let Err(e) = cli::save_database(&db, path) {
+            println!("error: {}", e);
+            failed = true;
         }
-        _ => {
-            println!("Unknown payload type: Self destructing")
+    }
+    if failed {
+        std::process::exit(1);
+    }
+}
+
+fn run_payload(payload_type: PayloadType) {
+    let (payload_name, source) = payload_type.scenario();
+
+    println!("Running test payload {:?}", payload_name);
+
+    let scenario = match scenario::parse(source) {
+        Ok(scenario) => scenario,
+        Err(e) => {
+            println!("Broken payload scenario: {}", e);
+            std::process::exit(1);
         }
     };
+    let (db, failures) = scenario::run(&scenario);
 
     println!("==============================Final Database State:===========================================");
     print_database(&db);
     println!("==============================================================================================");
 
-    println!("If this program didn't crash, were you lucky ? Check the logs\n");
+    if failures.is_empty() {
+        println!("Payload {} was stopped, final state is as expected\n", payload_name);
+        return;
+    }
+    println!("Payload {} did not end in the expected state:", payload_name);
+    for failure in &failures {
+        println!("  {}", failure);
+    }
+    std::process::exit(1);
 
     /*
     println!("Hello, world!"); // Placeholder main function
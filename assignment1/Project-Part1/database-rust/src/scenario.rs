@@ -0,0 +1,296 @@
+//declarative attack scenarios: a list of cli commands to run and the database state expected afterwards
+//
+//  # comment
+//  [operations]
+//  add Mallory mallory@nus.edu.sg malloryisnotevil
+//  !rename Mallory CCCC...        <- a leading ! means the command has to be rejected
+//  [expected]
+//  count 1                         <- optional, number of users left
+//  user Mallory id=1 inactivity=0  <- every user left has to be listed, only the given fields are checked
+//  absent Eve                      <- optional, for readability (unlisted users already fail)
+//
+//user fields are id, email, password, inactivity and active
+
+use crate::cli::{self, Command};
+use crate::{
+    find_user_by_username, get_password, init_database, u8_to_string_no_nullt, UserDatabase,
+};
+use std::fs;
+
+#[derive(Debug)]
+pub struct Operation {
+    pub line: usize,
+    pub command: Command,
+    pub should_fail: bool,
+}
+
+#[derive(Debug)]
+pub enum Expectation {
+    Count { line: usize, count: i32 },
+    User {
+        line: usize,
+        username: String,
+        fields: Vec<(String, String)>,
+    },
+    Absent { line: usize, username: String },
+}
+
+#[derive(Debug)]
+pub struct Scenario {
+    pub operations: Vec<Operation>,
+    pub expected: Vec<Expectation>,
+}
+
+const USER_FIELDS: [&str; 5] = ["id", "email", "password", "inactivity", "active"];
+
+fn parse_expectation(line: usize, tokens: &[&str]) -> Result<Expectation, String> {
+    match tokens {
+        ["count", count] => match count.parse::<i32>() {
+            Ok(count) => return Ok(Expectation::Count { line, count }),
+            Err(_) => return Err(format!("invalid count: {}", count)),
+        },
+        ["absent", username] => {
+            return Ok(Expectation::Absent {
+                line,
+                username: username.to_string(),
+            })
+        }
+        ["user", username, fields @ ..] => {
+            let mut parsed = Vec::new();
+            for field in fields {
+                let (key, value) = match field.split_once('=') {
+                    Some(pair) => pair,
+                    None => return Err(format!("expected key=value, got {}", field)),
+                };
+                if !USER_FIELDS.contains(&key) {
+                    return Err(format!("unknown user field: {}", key));
+                }
+                parsed.push((key.to_string(), value.to_string()));
+            }
+            return Ok(Expectation::User {
+                line,
+                username: username.to_string(),
+                fields: parsed,
+            });
+        }
+        _ => return Err("expected count, user or absent".to_string()),
+    }
+}
+
+pub fn parse(text: &str) -> Result<Scenario, String> {
+    let mut scenario = Scenario {
+        operations: Vec::new(),
+        expected: Vec::new(),
+    };
+    let mut section = "";
+    for (i, raw) in text.lines().enumerate() {
+        let line = i + 1;
+        //comments take the whole line, a '#' anywhere else is an error from tokenize
+        if raw.trim_start().starts_with('#') {
+            continue;
+        }
+        let tokens = cli::tokenize(raw).map_err(|e| format!("line {}: {}", line, e))?;
+        match tokens.as_slice() {
+            [] => continue,
+            ["[operations]"] | ["[expected]"] => {
+                section = tokens[0];
+                continue;
+            }
+            _ => {}
+        }
+        match section {
+            "[operations]" => {
+                let should_fail = tokens[0].starts_with('!');
+                let mut tokens = tokens;
+                tokens[0] = tokens[0].trim_start_matches('!');
+                let command =
+                    cli::parse_command(&tokens).map_err(|e| format!("line {}: {}", line, e))?;
+                scenario.operations.push(Operation {
+                    line,
+                    command,
+                    should_fail,
+                });
+            }
+            "[expected]" => {
+                let expectation =
+                    parse_expectation(line, &tokens).map_err(|e| format!("line {}: {}", line, e))?;
+                scenario.expected.push(expectation);
+            }
+            _ => return Err(format!("line {}: outside of [operations] or [expected]", line)),
+        }
+    }
+    if scenario.expected.is_empty() {
+        return Err("scenario has no [expected] state".to_string());
+    }
+    return Ok(scenario);
+}
+
+fn check_user(db: &UserDatabase, username: &str, fields: &[(String, String)]) -> Vec<String> {
+    let mut failures = Vec::new();
+    let user = match find_user_by_username(db, username) {
+        Some(user) => user,
+        None => return vec![format!("user {} is missing", username)],
+    };
+    for (key, expected) in fields {
+        let actual = match key.as_str() {
+            "id" => user.user_id.to_string(),
+            "email" => u8_to_string_no_nullt(&user.email),
+            "password" => get_password(db, username).unwrap_or_default(),
+            "inactivity" => user.inactivity_count.to_string(),
+            "active" => user.is_active.to_string(),
+            _ => unreachable!("fields are checked when parsing"),
+        };
+        if actual != *expected {
+            failures.push(format!(
+                "user {}: {} is {:?}, expected {:?}",
+                username, key, actual, expected
+            ));
+        }
+    }
+    return failures;
+}
+
+//runs the operations on a fresh database, returns it with a description of every expectation that failed
+pub fn run(scenario: &Scenario) -> (UserDatabase, Vec<String>) {
+    let mut db = init_database();
+    let mut failures = Vec::new();
+
+    for op in &scenario.operations {
+        match (cli::execute(&mut db, &op.command), op.should_fail) {
+            (Ok(()), true) => failures.push(format!(
+                "line {}: {:?} succeeded but should have been rejected",
+                op.line, op.command
+            )),
+            (Err(e), false) => failures.push(format!(
+                "line {}: {:?} failed: {}",
+                op.line, op.command, e
+            )),
+            (Err(e), true) => println!("rejected as expected: {}", e),
+            (Ok(()), false) => {}
+        }
+    }
+
+    let mut listed: Vec<String> = Vec::new();
+    for expectation in &scenario.expected {
+        match expectation {
+            Expectation::Count { line, count } => {
+                if db.count != *count {
+                    failures.push(format!(
+                        "line {}: {} users left, expected {}",
+                        line, db.count, count
+                    ));
+                }
+            }
+            Expectation::User {
+                line,
+                username,
+                fields,
+            } => {
+                for failure in check_user(&db, username, fields) {
+                    failures.push(format!("line {}: {}", line, failure));
+                }
+                listed.push(username.to_ascii_lowercase());
+            }
+            Expectation::Absent { line, username } => {
+                if find_user_by_username(&db, username).is_some() {
+                    failures.push(format!("line {}: user {} should be gone", line, username));
+                }
+            }
+        }
+    }
+    for user in db.users.iter().flatten() {
+        let username = u8_to_string_no_nullt(&user.username);
+        if !listed.contains(&username.to_ascii_lowercase()) {
+            failures.push(format!("unexpected user {} in the final state", username));
+        }
+    }
+
+    return (db, failures);
+}
+
+pub fn run_file(path: &str) -> Result<(), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path, e))?;
+    let scenario = parse(&text).map_err(|e| format!("{}: {}", path, e))?;
+    let (_, failures) = run(&scenario);
+    if !failures.is_empty() {
+        return Err(format!("{}:\n  {}", path, failures.join("\n  ")));
+    }
+    return Ok(());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::PayloadType;
+
+    fn assert_passes(payload_type: PayloadType) {
+        let (name, source) = payload_type.scenario();
+        let scenario = parse(source).unwrap_or_else(|e| panic!("{}: {}", name, e));
+        let (_, failures) = run(&scenario);
+        assert!(failures.is_empty(), "{}:\n  {}", name, failures.join("\n  "));
+    }
+
+    #[test]
+    fn out_of_bounds_payload() {
+        assert_passes(PayloadType::OUT_OF_BOUNDS_PAYLOAD);
+    }
+
+    #[test]
+    fn use_after_free_payload() {
+        assert_passes(PayloadType::USE_AFTER_FREE_PAYLOAD);
+    }
+
+    #[test]
+    fn double_free_payload() {
+        assert_passes(PayloadType::DOUBLE_FREE_PAYLOAD);
+    }
+
+    //new attacks only need a file in scenarios/
+    #[test]
+    fn scenario_files() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/scenarios");
+        let mut failures = Vec::new();
+        for entry in fs::read_dir(dir).expect("scenarios directory") {
+            let path = entry.expect("scenario file").path();
+            if path.extension().map_or(false, |ext| ext == "scenario") {
+                if let Err(e) = run_file(&path.to_string_lossy()) {
+                    failures.push(e);
+                }
+            }
+        }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn hash_only_starts_a_comment_line() {
+        let scenario = parse(
+            "  # comment\n\
+             [operations]\n\
+             add Alice alice@nus.edu.sg pa#ss\n\
+             [expected]\n\
+             count 1\n",
+        );
+        assert_eq!(
+            scenario.unwrap_err(),
+            "line 3: '#' is not allowed in commands"
+        );
+    }
+
+    #[test]
+    fn wrong_expectations_are_reported() {
+        let scenario = parse(
+            "[operations]\n\
+             add Alice alice@nus.edu.sg pw\n\
+             add Bob bob@nus.edu.sg pw\n\
+             !login Alice\n\
+             [expected]\n\
+             user Alice id=2 inactivity=0\n",
+        )
+        .unwrap();
+        let (_, failures) = run(&scenario);
+        assert_eq!(failures.len(), 3, "{:?}", failures);
+        assert!(failures[0].contains("should have been rejected"));
+        assert!(failures[1].contains("id is \"1\", expected \"2\""));
+        assert!(failures[2].contains("unexpected user Bob"));
+    }
+}
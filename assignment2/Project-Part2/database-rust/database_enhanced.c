@@ -1,17 +1,20 @@
+#include <stdarg.h>
 #include <stdio.h>
 #include <stdlib.h>
 #include <string.h>
+#include <strings.h>
 #include <time.h>
 
 // Read Only - Do not modify
 #define MAX_USERS 1000
 #define MAX_NAME_LEN 50
-#define INACTIVITY_THRESHOLD 5
 #define MAX_EMAIL_LEN 50
 #define MAX_PASSWORD_LENGTH 100
 #define SESSION_MAX_IDLE_TIME 1
 #define MAX_SESSIONS 100
 #define MAX_SESSION_TOKEN_LEN 32
+// Printed instead of a password, hash or session token (same as REDACTED in database_fix_full.rs)
+#define REDACTED "***"
 
 typedef enum {
     C_OWNED = 0,
@@ -20,8 +23,40 @@ typedef enum {
     SHARED_C_PRIMARY = 3
 } OwnershipType;
 
+// Which side allocated a user, only that side's allocator frees it (same values as AllocatorType
+// in database_fix_full.rs)
+typedef enum {
+    RUST_ALLOCATED = 0,
+    C_ALLOCATED = 1
+} AllocatorType;
+
+// Retention class of a user, set at signup and never derived from the username (same values as
+// Role in database_fix_full.rs)
+typedef enum {
+    ROLE_STUDENT = 0,
+    ROLE_STAFF = 1,
+    ROLE_ADMIN = 2
+} UserRole;
+
+// Same layout as PasswordHash in database_fix_full.rs - the plaintext password is never stored
+#define PASSWORD_SALT_LEN 16
+#define PASSWORD_HASH_LEN 32
+
+typedef struct {
+    unsigned int version;
+    unsigned int iterations;
+    unsigned char salt[PASSWORD_SALT_LEN];
+    unsigned char hash[PASSWORD_HASH_LEN];
+} PasswordHash_t;
+
+// Implemented in Rust (database_wrapper.rs) so both backends use the same hashing scheme
+// returns -1 (and leaves out untouched) for passwords outside the length limit below, or when no
+// salt could be generated
+extern int db_hash_password(const char* password, PasswordHash_t* out);
+extern int db_verify_password(const PasswordHash_t* record, const char* candidate);
+
 typedef struct {
-    char password[MAX_PASSWORD_LENGTH];
+    PasswordHash_t password_hash;
     char username[MAX_NAME_LEN];
     int user_id;
     char email[MAX_EMAIL_LEN];
@@ -29,8 +64,100 @@ typedef struct {
     int is_active;
     char session_token[MAX_SESSION_TOKEN_LEN];
     OwnershipType ownership;
+    // Number of databases (this one and/or the Rust one) holding the user
+    int ref_count;
+    AllocatorType allocator;
+    UserRole role;
 } UserStruct_t;
 
+// Implemented in Rust (database_fix_full.rs). Every database holding a user holds one reference,
+// user_release returns the references left and frees the user with the allocator that created it
+// (free() for C_ALLOCATED users) once there are none
+extern int user_retain(UserStruct_t* user);
+extern int user_release(UserStruct_t* user);
+
+// Implemented in Rust (database_fix_full.rs), which holds the table of legal ownership changes.
+// ownership is never assigned here once a user exists: user_transition_ownership returns -1 and
+// leaves it as it was for an illegal transition, user_check_c_release returns 0 when C does not
+// hold the user and must not release it. A strict-ownership build aborts in both cases instead,
+// naming the user and call_site (pass __func__)
+extern int user_transition_ownership(UserStruct_t* user, int to, const char* call_site);
+extern int user_check_c_release(UserStruct_t* user, const char* call_site);
+
+// Implemented in Rust (alloc_registry.rs), no-ops unless the allocation registry is enabled
+// (--track-allocations). Called when C allocates a user, when the C database takes or drops a
+// reference to one, and by free_user before it reads the user: alloc_registry_check_freed returns
+// 1 for a user that was already freed
+extern void alloc_registry_allocated(const UserStruct_t* user);
+extern void alloc_registry_c_aliased(const UserStruct_t* user);
+extern void alloc_registry_c_unaliased(const UserStruct_t* user);
+extern int alloc_registry_check_freed(const UserStruct_t* user, const char* call_site);
+
+// What a database operation did with the user it was given (DatabaseOperationResult in
+// database_wrapper.rs), so the caller acts on it instead of reading OwnershipType afterwards:
+//   success                the operation was carried out
+//   requires_deallocation  C did not take the caller's reference, the caller still has to release it
+//   ownership_transfer     a reference changed hands: C took the caller's (add_user) or let go of
+//                          its own (free_user, merge_duplicate_handles when it dropped a duplicate)
+//   updated_pointer        the user to use from now on - the same one, or NULL once nothing holds
+//                          it and it has been freed. NULL for operations on the whole database
+//   count                  how many users an operation on the whole database affected: the
+//                          duplicates merge_duplicate_handles dropped, the users
+//                          memory_pressure_cleanup moved
+typedef struct {
+    int success;
+    int requires_deallocation;
+    int ownership_transfer;
+    void* updated_pointer;
+    int count;
+} DatabaseOperationResult;
+
+// Called by merge_duplicate_handles for every duplicate it drops, before the duplicate is released:
+// merged is the handle being dropped, survivor the one that stays in the database for that user
+typedef void (*MergeCallback)(void* context, const UserStruct_t* merged, const UserStruct_t* survivor);
+
+// Same values as RetentionDecision in database_fix_full.rs
+typedef enum {
+    RETENTION_KEEP = 0,
+    RETENTION_DEACTIVATE = 1,
+    RETENTION_REMOVE = 2
+} RetentionDecision_t;
+
+// Implemented in Rust (database_wrapper.rs). rust_db is the Rust database, opaque here and only passed back:
+// db_retention_decide evaluates its retention policy so both backends remove the same users,
+// db_archive_user copies a user C is about to free into its archive so it can be restored later
+extern int db_retention_decide(void* rust_db, int role, int inactivity_count);
+extern void db_archive_user(void* rust_db, const UserStruct_t* user);
+
+// Implemented in Rust (database_wrapper.rs), writes to the same audit trail as the Rust side
+// detail is a space separated list of key=value pairs, user may be NULL
+extern void db_audit_event(int day, const char* event, const UserStruct_t* user, const char* detail);
+
+// Same values as log::Level on the Rust side
+#define LOG_ERROR 1
+#define LOG_WARN 2
+#define LOG_INFO 3
+#define LOG_DEBUG 4
+#define LOG_TRACE 5
+
+// Implemented in Rust (database_wrapper.rs), C logs through the same logger as the Rust code
+// under the target "c", so the DB_LOG / --log filter and --quiet apply to both backends
+extern int db_log_enabled(int level);
+extern void db_log_message(int level, const char* message);
+
+void db_log(int level, const char* fmt, ...) {
+    // nothing is formatted for a level the filter drops
+    if (!db_log_enabled(level)) {
+        return;
+    }
+    char message[512];
+    va_list args;
+    va_start(args, fmt);
+    vsnprintf(message, sizeof(message), fmt, args);
+    va_end(args);
+    db_log_message(level, message);
+}
+
 typedef struct {
     UserStruct_t *users[MAX_USERS];
     int count;
@@ -55,53 +182,209 @@ typedef struct {
 static SessionManager_t* global_session_manager = NULL;
 static UserDatabase_t* global_db = NULL;
 int *global_day_counter;
+// Shared with the Rust database (IdAllocator::next_user_id) so ids are never reused across backends
+int *global_next_user_id;
+static int last_user_id = 0;
+
+// Overwrites credentials before their memory is released. The volatile pointer keeps the
+// compiler from dropping the writes as dead stores to memory that is about to be freed
+void secure_wipe(void* ptr, size_t len) {
+    volatile unsigned char* p = (volatile unsigned char*)ptr;
+    while (len--) {
+        *p++ = 0;
+    }
+}
 
 // Core database functions
-UserDatabase_t* init_database(const int *dc) {
+UserDatabase_t* init_database(const int *dc, int *next_user_id) {
     UserDatabase_t* db = malloc(sizeof(UserDatabase_t));
     db->count = 0;
     db->capacity = MAX_USERS;
     global_db = db;
     global_day_counter = (int*)dc;
+    global_next_user_id = next_user_id;
     return global_db;
 }
 
-void free_user(UserStruct_t* user) {
-    #ifdef DEBUG_EN
-    printf("[C-Code] Freeing user: %s\n", user->username);
-    #endif
-    printf("[C] ATTEMPTING TO FREE USER AT %p\n", (void*)user);
-    if (user != NULL) {
-        if (user->ownership == C_OWNED) {
-            printf("[C] FREEING A C OWNED USER: %s - OWNERSHIP: %d\n", user->username, user->ownership);
-            free(user);
-        } else {
-            printf("[C] SKIPPING USER NOW C OWNED: %s\n", user->username);
+void audit_event(const char* event, const UserStruct_t* user, const char* detail) {
+    db_audit_event(global_day_counter ? *global_day_counter : 0, event, user, detail);
+}
+
+int allocate_user_id() {
+    return (*global_next_user_id)++;
+}
+
+int get_last_user_id() {
+    return last_user_id;
+}
+
+// Handles Rust keeps instead of UserStruct_t pointers (UserHandle in database_wrapper.rs).
+// The table is separate from db->users, so compaction moving a user does not touch its handle.
+// A slot's generation is bumped whenever C frees or drops the user in it, which makes every
+// handle issued for that slot before resolve to NULL
+typedef struct {
+    unsigned int slot;
+    unsigned int generation;
+} UserHandle_t;
+
+typedef struct {
+    UserStruct_t* user;
+    unsigned int generation;
+} HandleSlot_t;
+
+static HandleSlot_t handle_table[MAX_USERS];
+
+// slot is MAX_USERS (never valid) when the table is full
+UserHandle_t issue_user_handle(UserStruct_t* user) {
+    UserHandle_t handle = { MAX_USERS, 0 };
+    int free_slot = -1;
+    for (int i = 0; i < MAX_USERS; i++) {
+        if (handle_table[i].user == user) {
+            handle.slot = i;
+            handle.generation = handle_table[i].generation;
+            return handle;
+        }
+        if (free_slot < 0 && handle_table[i].user == NULL) {
+            free_slot = i;
         }
     }
+    if (user == NULL || free_slot < 0) {
+        return handle;
+    }
+    handle_table[free_slot].user = user;
+    handle.slot = free_slot;
+    handle.generation = handle_table[free_slot].generation;
+    return handle;
 }
 
-void add_user(UserDatabase_t* db, UserStruct_t* user) {
-    printf("[C] ADDING USER: %s WITH OWNERSHIP: %d\n", user->username, user->ownership);
-    if (db->count >= MAX_USERS) {
-        #ifdef DEBUG_En
-        printf("[C-Code] DB Full, cannot add user: %s\n", user->username);
-        #endif
-        free_user(user);
+UserStruct_t* resolve_user_handle(UserHandle_t handle) {
+    if (handle.slot >= MAX_USERS) {
+        return NULL;
+    }
+    HandleSlot_t* slot = &handle_table[handle.slot];
+    if (slot->user == NULL || slot->generation != handle.generation) {
+        return NULL;
+    }
+    return slot->user;
+}
+
+void invalidate_user_handles(UserStruct_t* user) {
+    if (user == NULL) {
         return;
     }
+    for (int i = 0; i < MAX_USERS; i++) {
+        if (handle_table[i].user == user) {
+            handle_table[i].user = NULL;
+            handle_table[i].generation++;
+        }
+    }
+}
 
+// Every path where C lets go of a user goes through here (the free_user macro below, which passes
+// the caller as call_site), so its handles are invalidated whether or not the user is freed. It
+// releases the reference C held: the user is only freed (wiped first) once the Rust database does
+// not hold it either. A user C does not own is left alone (success 0)
+DatabaseOperationResult free_user_at(UserStruct_t* user, const char* call_site) {
+    DatabaseOperationResult result = { 0, 0, 0, NULL, 0 };
+    if (user != NULL) {
+        // a user freed before must not be read, not even for the log lines
+        if (alloc_registry_check_freed(user, call_site)) {
+            return result;
+        }
+        db_log(LOG_TRACE, "Freeing user: %s", user->username);
+        db_log(LOG_TRACE, "ATTEMPTING TO FREE USER AT %p", (void*)user);
+        if (!user_check_c_release(user, call_site)) {
+            return result;
+        }
+    }
+    invalidate_user_handles(user);
     if (user != NULL) {
-        #ifdef DEBUG_EN
-        printf("[C-Code] Adding user: %s\n increasing count to %d\n", user->username, db->count + 1);
-        #endif
-        printf("[C] OWNERSHIP BEFORE: %d\n", user->ownership);
-        user->user_id = db->count + 1;
-        db->users[db->count++] = user;
-        printf("[C] OWNERSHIP AFTER: %d\n", user->ownership);
+        db_log(LOG_DEBUG, "RELEASING USER: %s - OWNERSHIP: %d REFERENCES: %d", user->username, user->ownership, user->ref_count);
+        alloc_registry_c_unaliased(user);
+        // the Rust database keeps a shared user on its own
+        int shared = user->ownership == SHARED_RUST_PRIMARY || user->ownership == SHARED_C_PRIMARY;
+        if (shared && user->ref_count > 1) {
+            user_transition_ownership(user, RUST_OWNED, call_site);
+        }
+        result.success = 1;
+        result.ownership_transfer = 1;
+        // still allocated while the Rust database holds it
+        if (user_release(user) > 0) {
+            result.updated_pointer = user;
+        }
     }
+    return result;
 }
+#define free_user(user) free_user_at((user), __func__)
 
+// The database takes over one reference to the user from the caller, unless it is full - then the
+// reference stays with the caller (requires_deallocation), which decides what happens to the user.
+// user may be NULL (create_user rejected the password), nothing is added then
+DatabaseOperationResult add_user(UserDatabase_t* db, UserStruct_t* user) {
+    DatabaseOperationResult result = { 0, 0, 0, user, 0 };
+    if (user == NULL) {
+        db_log(LOG_WARN, "ADD_USER CALLED WITHOUT A USER - SKIPPING");
+        return result;
+    }
+    db_log(LOG_DEBUG, "ADDING USER: %s WITH OWNERSHIP: %d", user->username, user->ownership);
+    if (db->count >= MAX_USERS) {
+        db_log(LOG_WARN, "DB Full, cannot add user: %s", user->username);
+        result.requires_deallocation = 1;
+        return result;
+    }
+
+    db_log(LOG_TRACE, "Adding user: %s, increasing count to %d", user->username, db->count + 1);
+    db_log(LOG_TRACE, "OWNERSHIP BEFORE: %d", user->ownership);
+    // users shared from Rust already have an id from the shared allocator
+    if (user->user_id <= 0) {
+        user->user_id = allocate_user_id();
+    }
+    last_user_id = user->user_id;
+    db->users[db->count++] = user;
+    alloc_registry_c_aliased(user);
+    db_log(LOG_TRACE, "OWNERSHIP AFTER: %d", user->ownership);
+    result.success = 1;
+    result.ownership_transfer = 1;
+    return result;
+}
+
+
+// Called by Rust right before it drops a user it owns - removes every alias of it from the
+// C database and releases the references they held (Rust still holds its own, so this never frees)
+void forget_user_reference(UserStruct_t* user) {
+    if (global_db == NULL || user == NULL) {
+        return;
+    }
+    invalidate_user_handles(user);
+    int aliases = 0;
+    for (int i = 0; i < global_db->count; i++) {
+        if (global_db->users[i] == user) {
+            db_log(LOG_DEBUG, "FORGETTING REFERENCE TO RUST USER: %s", user->username);
+            global_db->users[i] = NULL;
+            alloc_registry_c_unaliased(user);
+            aliases++;
+        }
+    }
+    if (aliases > 0) {
+        user_transition_ownership(user, RUST_OWNED, __func__);
+    }
+    while (aliases--) {
+        user_release(user);
+    }
+}
+
+// Used when restoring a snapshot - users that C allocated are allocated by C again
+UserStruct_t* clone_user_record(const UserStruct_t* src) {
+    UserStruct_t* user = malloc(sizeof(UserStruct_t));
+    if (user == NULL) {
+        return NULL;
+    }
+    *user = *src;
+    user->ref_count = 1;
+    user->allocator = C_ALLOCATED;
+    alloc_registry_allocated(user);
+    return user;
+}
 
 void cleanup_database(UserDatabase_t* db) {
     for (int i = 0; i < db->count; i++) {
@@ -116,8 +399,10 @@ void print_database(UserDatabase_t *db) {
         if (db->users[i] == NULL) {
             continue;
         }
-        printf("User: %s, ID: %d, Email: %s, Inactivity: %d  Password = %s\n", db->users[i]->username, db->users[i]->user_id, db->users[i]->email, db->users[i]->inactivity_count, db->users[i]->password);
+        printf("User: %s, ID: %d, Email: %s, Inactivity: %d\n", db->users[i]->username, db->users[i]->user_id, db->users[i]->email, db->users[i]->inactivity_count);
     }
+    // stdout is block buffered when piped, flush so the dump stays in order with the Rust output
+    fflush(stdout);
 }
 
 void copy_string(char* dest, char* src, size_t n) {
@@ -132,21 +417,52 @@ int get_current_time() {
     return time(NULL);
 }
 
-UserStruct_t* create_user(char* username, char* email, int user_id, char* password) {
+UserStruct_t* create_user_with_hash(char* username, char* email, int user_id, const PasswordHash_t* password_hash) {
     UserStruct_t* user = malloc(sizeof(UserStruct_t));
+    if (user == NULL) {
+        db_log(LOG_ERROR, "Could not allocate user %s", username);
+        return NULL;
+    }
 
     copy_string(user->username, username, MAX_NAME_LEN);
     copy_string(user->email, email, MAX_EMAIL_LEN);
-    copy_string(user->password, password, MAX_PASSWORD_LENGTH);
+    user->password_hash = *password_hash;
+    user->session_token[0] = '\0';
 
     user->user_id = user_id;
     user->inactivity_count = 0;
     user->is_active = 1;
     user->ownership = C_OWNED;
-    printf("[C] Created C-owned user at %p\n", (void*)user);
+    // the caller's reference, handed to the database by add_user
+    user->ref_count = 1;
+    user->allocator = C_ALLOCATED;
+    // the caller gives the user its role once it is created
+    user->role = ROLE_STUDENT;
+    alloc_registry_allocated(user);
+    db_log(LOG_DEBUG, "Created C-owned user at %p", (void*)user);
 
     return user;
 }
+
+// same limit as MAX_PASSWORD_LENGTH in password_policy.rs, longer passwords are rejected, not cut
+int password_length_ok(const char* password) {
+    if (password == NULL) {
+        return 0;
+    }
+    size_t len = strnlen(password, MAX_PASSWORD_LENGTH);
+    return len > 0 && len < MAX_PASSWORD_LENGTH;
+}
+
+UserStruct_t* create_user(char* username, char* email, int user_id, char* password) {
+    PasswordHash_t password_hash;
+    if (!password_length_ok(password) || db_hash_password(password, &password_hash) != 0) {
+        db_log(LOG_WARN, "Rejected password for %s", username);
+        return NULL;
+    }
+    UserStruct_t* user = create_user_with_hash(username, email, user_id, &password_hash);
+    secure_wipe(&password_hash, sizeof(password_hash));
+    return user;
+}
 void update_day_counter(int *day_counter) {
     global_day_counter = day_counter;
 }
@@ -172,9 +488,7 @@ int init_session_manager() {
     global_session_manager->session_count = 0;
     global_session_manager->db_ref = global_db;
     memset(global_session_manager->sessions, 0, sizeof(SessionInfo_t*) * MAX_SESSIONS);
-    #ifdef DEBUG_EN
-    printf("[C-Code] Session manager initialized\n");
-    #endif
+    db_log(LOG_TRACE, "Session manager initialized");
     return 0;
 }
 
@@ -196,9 +510,7 @@ char* create_user_session(UserStruct_t *user) {
     }
 
     if (global_session_manager->session_count >= MAX_SESSIONS) {
-        #ifdef DEBUG_EN
-        printf("[C-Code] Too many active sessions\n");
-        #endif
+        db_log(LOG_ERROR, "Too many active sessions");
         exit(1);
     }
 
@@ -215,12 +527,21 @@ char* create_user_session(UserStruct_t *user) {
     global_session_manager->sessions[global_session_manager->session_count] = session;
     global_session_manager->session_count++;
 
-    #ifdef DEBUG_EN
-    printf("Created session for user %d: %s\n", user_id, token);
-    #endif
+    // never the token itself, only who it belongs to
+    db_log(LOG_TRACE, "Created session for user %d: %s", user->user_id, REDACTED);
     return token;
 }
 
+// Every token returned by create_user_session / user_login is malloc'd for the caller,
+// which hands it back here once it has made its own copy
+void free_session_token(char* token) {
+    if (token == NULL) {
+        return;
+    }
+    secure_wipe(token, MAX_SESSION_TOKEN_LEN);
+    free(token);
+}
+
 // Memory management and optimization functions
 int get_non_null_ref_count(UserDatabase_t* db) {
     int count = 0;
@@ -241,17 +562,13 @@ UserStruct_t** get_user_reference_for_debugging(UserDatabase_t* db) {
         return NULL;
     }
 
-    #ifdef DEBUG_EN
-    printf("[C-Code] Scanning database for non-null users... among %d users\n", db->count);
-    #endif
+    db_log(LOG_TRACE, "Scanning database for non-null users... among %d users", db->count);
 
     int index = 0;
     for(int i = 0; i < db->count; i++) {
         UserStruct_t* useri = db->users[i];
         if(useri!=NULL){
-            #ifdef DEBUG_EN
-            printf("[C-Code] Adding user reference for %s\n", useri->username);
-            #endif
+            db_log(LOG_TRACE, "Adding user reference for %s", useri->username);
             user[index++] = useri;
         }
     }
@@ -263,33 +580,39 @@ UserStruct_t** get_user_reference_for_debugging(UserDatabase_t* db) {
 void clone_user(UserStruct_t* src, UserStruct_t* dest) {
     copy_string(dest->username, src->username, MAX_NAME_LEN);
     copy_string(dest->email, src->email, MAX_EMAIL_LEN);
-    copy_string(dest->password, src->password, MAX_PASSWORD_LENGTH);
+    dest->password_hash = src->password_hash;
     dest->inactivity_count = src->inactivity_count;
     copy_string(dest->session_token, src->session_token, MAX_SESSION_TOKEN_LEN);
     dest->is_active = src->is_active;
 }
 
 //Hint : Interesting function
-void memory_pressure_cleanup(UserDatabase_t* db) {
-    #ifdef DEBUG_EN
-    printf("[C-Code] System under memory pressure - performing selective cleanup\n");
-    #endif
+// Only moves pointers within db->users, no user changes hands and none is freed. count is the
+// number of users moved
+DatabaseOperationResult memory_pressure_cleanup(UserDatabase_t* db) {
+    DatabaseOperationResult result = { 1, 0, 0, NULL, 0 };
+    db_log(LOG_INFO, "System under memory pressure - performing selective cleanup");
     // shift users together and compact the array
     int write_index = 0;
+    int moved = 0;
     for (int i = 0; i < db->count; i++) {
         if (db->users[i] != NULL) {
             if (write_index != i) {
                 db->users[write_index] = db->users[i];
                 db->users[i] = NULL;
+                moved++;
             }
             write_index++;
         }
     }
 
+    char detail[96];
+    snprintf(detail, sizeof(detail), "backend=c moved=%d slots_before=%d slots_after=%d", moved, db->count, write_index);
+    audit_event("compaction", NULL, detail);
     db->count = write_index;
-    #ifdef DEBUG_EN
-    printf("Memory pressure cleanup completed\n");
-    #endif
+    db_log(LOG_INFO, "Memory pressure cleanup completed");
+    result.count = moved;
+    return result;
 }
 
 
@@ -320,7 +643,10 @@ int validate_user_session(char* token) {
     return 0;
 }
 
-void merge_duplicate_handles(UserDatabase_t *db){
+// Several users can be merged in one call, on_merge (may be NULL) is told about each one with the
+// handle that survives it. count is the number of duplicates dropped
+DatabaseOperationResult merge_duplicate_handles(UserDatabase_t *db, MergeCallback on_merge, void* context){
+    DatabaseOperationResult result = { 1, 0, 0, NULL, 0 };
     for(int i = (db->count-1); i >= 0; i--){
         if (!db->users[i]) {
             continue;
@@ -329,42 +655,62 @@ void merge_duplicate_handles(UserDatabase_t *db){
             if (!db->users[j]) {
                 continue;
             }
-            if(strcmp(db->users[i]->username, db->users[j]->username) == 0 && strcmp(db->users[i]->email, db->users[j]->email) == 0 && strcmp(db->users[i]->password, db->users[j]->password) == 0){
-                #ifdef DEBUG_EN
-                printf("[C-Code] Merging duplicate user handles for %s\n", db->users[i]->username);
-                #endif
+            // Duplicates are the same username and email. The password hash is not compared: every
+            // hash has its own random salt, so two handles of one user never have equal hashes even
+            // with the same password. The later handle (i) is kept, it has the latest password
+            if(strcmp(db->users[i]->username, db->users[j]->username) == 0 && strcmp(db->users[i]->email, db->users[j]->email) == 0){
+                db_log(LOG_INFO, "Merging duplicate user handles for %s", db->users[i]->username);
                 UserStruct_t* to_free = db->users[j];
+                char detail[64];
+                snprintf(detail, sizeof(detail), "backend=c freed=%d", to_free->ref_count == 1);
+                audit_event("duplicate_merged", to_free, detail);
+                if (on_merge != NULL) {
+                    on_merge(context, to_free, db->users[i]);
+                }
                 db->users[j] = NULL;
                 free_user(to_free);
-                break;
+                result.ownership_transfer = 1;
+                result.count++;
             }
         }
     }
     //memory_pressure_cleanup(db);
+    return result;
 }
 
-void update_database_daily(UserDatabase_t* db) {
-    printf("[C] UPDATING DATABASE!\n");
-    printf("[C] CURRENT DB COUNT: %d\n", db->count);
+void update_database_daily(UserDatabase_t* db, void* rust_db) {
+    db_log(LOG_TRACE, "UPDATING DATABASE!");
+    db_log(LOG_TRACE, "CURRENT DB COUNT: %d", db->count);
 
     for (int i = 0; i < db->count; i++) {
-        printf("[C] ITERATING THROUGH USERS: %d\n", i);
+        db_log(LOG_TRACE, "ITERATING THROUGH USERS: %d", i);
 
         if (db->count >= MAX_USERS) {
-            printf("[C] USER LIMIT REAHED!\n");
+            db_log(LOG_WARN, "USER LIMIT REAHED!");
             return;
         }
 
         if (db->users[i] == NULL) {
-            printf("[C] USER IS NULL - SKIPPING!\n");
+            db_log(LOG_TRACE, "USER IS NULL - SKIPPING!");
+            continue;
+        }
+
+        // A shared user is the same allocation the Rust database holds, and the Rust daily update
+        // (which runs first) already aged it and applied the retention policy. Aging it here too
+        // would count every day twice and remove it here before Rust does
+        if (db->users[i]->ownership == SHARED_RUST_PRIMARY || db->users[i]->ownership == SHARED_C_PRIMARY) {
+            db_log(LOG_TRACE, "USER %s IS SHARED - AGED BY RUST", db->users[i]->username);
             continue;
         }
 
-        if (!db->users[i]->is_active && db->users[i]->inactivity_count > INACTIVITY_THRESHOLD) {
-            //#ifdef DEBUG_EN
-            printf("[C-Code] Removing user[%d] %s due to inactivity for %d days\n", db->users[i]->user_id, db->users[i]->username, db->users[i]->inactivity_count);
-            //#endif
-            printf("[C] FREEING USER BECAUSE INACTIVE!\n");
+        int decision = db_retention_decide(rust_db, db->users[i]->role, db->users[i]->inactivity_count);
+        if (decision == RETENTION_REMOVE) {
+            db_log(LOG_INFO, "Removing user[%d] %s due to inactivity for %d days", db->users[i]->user_id, db->users[i]->username, db->users[i]->inactivity_count);
+            db_log(LOG_DEBUG, "ARCHIVING AND FREEING USER BECAUSE INACTIVE!");
+            char detail[96];
+            snprintf(detail, sizeof(detail), "backend=c inactive_days=%d freed=%d", db->users[i]->inactivity_count, db->users[i]->ref_count == 1);
+            audit_event("inactivity_removal", db->users[i], detail);
+            db_archive_user(rust_db, db->users[i]);
             free_user(db->users[i]);
             db->users[i] = NULL;
 
@@ -377,38 +723,33 @@ void update_database_daily(UserDatabase_t* db) {
             } else {
                 db->users[i]->is_active = 1;
             }
+            if (decision == RETENTION_DEACTIVATE) {
+                db->users[i]->is_active = 0;
+            }
 
 
-            //#ifdef DEBUG_EN
-            printf("[C-Code] %d is max allowed threshold Incrementing inactivity for user[%d] %s to %d days\n", INACTIVITY_THRESHOLD, db->users[i]->user_id, db->users[i]->username, db->users[i]->inactivity_count + 1);
-            //#endif
-            printf("[C] INCREMENTING INACTIVITY!\n");
-            printf("[C] CURRENT USER: %s - INACTIVITY COUNT: %d\n", db->users[i]->username, db->users[i]->inactivity_count);
+            db_log(LOG_TRACE, "Retention policy kept user[%d] %s, incrementing inactivity to %d days", db->users[i]->user_id, db->users[i]->username, db->users[i]->inactivity_count + 1);
+            db_log(LOG_TRACE, "INCREMENTING INACTIVITY!");
+            db_log(LOG_TRACE, "CURRENT USER: %s - INACTIVITY COUNT: %d", db->users[i]->username, db->users[i]->inactivity_count);
             db->users[i]->inactivity_count++;
-            printf("[C] INACTIVITY COUNT INCREASED TO %d FOR USER %d\n", db->users[i]->inactivity_count, db->users[i]->user_id);
+            db_log(LOG_TRACE, "INACTIVITY COUNT INCREASED TO %d FOR USER %d", db->users[i]->inactivity_count, db->users[i]->user_id);
     }
 
-    printf("[C] CURRENT DAY: %d\n", *global_day_counter);
-
-    if(*global_day_counter % 4 == 0){
-        printf("[C] CALLING MERGE DUPLICATE HANDLES()\n");
-        merge_duplicate_handles(db);
-    }
-
-    if (*global_day_counter % 8 == 0){
-        printf("CALLING MEMORY PRESSURE CLEANUP()\n");
-        memory_pressure_cleanup(db);
-    }
+    db_log(LOG_TRACE, "CURRENT DAY: %d", *global_day_counter);
+    // merge_duplicate_handles (every 4th day) and memory_pressure_cleanup (every 8th) are run right
+    // after this by DatabaseExtensions::increment_day, which acts on what they return
 }
 
+// Usernames compare ignoring ASCII case, the same rule as normalize_username in database_fix_full.rs,
+// so a name is taken in both backends or in neither
 UserStruct_t* find_user_by_username(UserDatabase_t* db, char* user_name) {
-    printf("[C] FINDING USER BY USERNAME: %s", user_name);
+    db_log(LOG_TRACE, "FINDING USER BY USERNAME: %s", user_name);
     for (int i = 0; i < db->count; i++) {
         if (db->users[i] == NULL) {
-            printf("USER IS NULL!\n");
+            db_log(LOG_TRACE, "USER IS NULL!");
             continue;
         }
-        if (strcmp(db->users[i]->username, user_name) == 0) {
+        if (strcasecmp(db->users[i]->username, user_name) == 0) {
             return db->users[i];
         }
     }
@@ -419,9 +760,7 @@ UserStruct_t* find_user_by_username(UserDatabase_t* db, char* user_name) {
 char* user_login(UserDatabase_t* db, char* user_name) {
     UserStruct_t* user = find_user_by_username(db, user_name);
 
-    #ifdef DEBUG_EN
-    printf("[C-Code] User[%d] %s logged in after %d days\n", user->user_id, user->username, user->inactivity_count);
-    #endif
+    db_log(LOG_TRACE, "User[%d] %s logged in after %d days", user->user_id, user->username, user->inactivity_count);
     user->inactivity_count = 0;
     char *token = create_user_session(user);
     copy_string(user->session_token, token, MAX_SESSION_TOKEN_LEN);
@@ -429,12 +768,13 @@ char* user_login(UserDatabase_t* db, char* user_name) {
     return token;
 }
 
-char* get_password(UserDatabase_t* db, char* username) {
+int verify_password(UserDatabase_t* db, char* username, char* candidate) {
     UserStruct_t* user = find_user_by_username(db, username);
-    #ifdef DEBUG_EN
-        printf("Password  Request for User[%d] %s is %s\n", user->user_id, user->username, user->password);
-    #endif
-    return user->password;
+    if (user == NULL || !password_length_ok(candidate)) {
+        return 0;
+    }
+    db_log(LOG_TRACE, "Password check for User[%d] %s", user->user_id, user->username);
+    return db_verify_password(&user->password_hash, candidate);
 }
 
 
@@ -463,6 +803,7 @@ void deactivate_users(UserDatabase_t* rust_db) {
         user = find_user_by_session_token(rust_db, global_session_manager->sessions[i]->session_token);
         user->is_active = 0;
 
+        secure_wipe(global_session_manager->sessions[i]->session_token, MAX_SESSION_TOKEN_LEN);
         free(global_session_manager->sessions[i]);
     }
 }
@@ -0,0 +1,442 @@
+/* ------alloc_registry.rs------
+ *
+ * opt-in shadow registry of every user allocation, so ownership bugs show up in one report instead
+ * of being pieced together from log lines. each record is tracked with the language that allocated
+ * it, the simulation day, its current owner and the references each database holds to it:
+ *
+ *   rust   UserRecord::new / from_raw / retain and UserRecord's drop (database_fix_full.rs)
+ *   C      create_user_with_hash (so create_user too), clone_user_record, add_user, free_user and
+ *          forget_user_reference, through the alloc_registry_* functions at the bottom
+ *
+ * user_release reports when a record is actually freed. report() is made once the rust database
+ * is dropped (the C one is never torn down, what it holds is only counted) and lists
+ *   leaks                    records never freed that the C database does not hold - including
+ *                            rust references nothing dropped, such as a forgotten UserRecord
+ *   double frees             a release of a record that was freed already - refused while the
+ *                            registry is on, so the freed memory is never read
+ *   frees while referenced   records freed while a database still referenced them
+ *
+ * nothing is recorded until enable() is called (--track-allocations). records are keyed by address,
+ * an address that is freed and allocated again starts over
+ *
+ * included by database_fix_full.rs through #[path], so it is built into both binaries
+ */
+
+use super::{c_call_site, AllocatorType, OwnershipType, UserStruct};
+use std::collections::HashMap;
+use std::os::raw::{c_char, c_int};
+use std::sync::{Mutex, MutexGuard};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Database {
+    Rust,
+    C,
+}
+
+impl Database {
+    fn name(self) -> &'static str {
+        match self {
+            Database::Rust => "rust",
+            Database::C => "C",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Allocation {
+    username: String,
+    user_id: i32,
+    allocator: AllocatorType,
+    day: i32,
+    owner: OwnershipType,
+    rust_refs: i32,
+    c_refs: i32,
+}
+
+impl Allocation {
+    fn describe(&self) -> String {
+        let language = match self.allocator {
+            AllocatorType::RUST_ALLOCATED => "rust",
+            AllocatorType::C_ALLOCATED => "C",
+        };
+        format!(
+            "{:?} (id {}) allocated by {} on day {}, owner {:?}",
+            self.username, self.user_id, language, self.day, self.owner
+        )
+    }
+
+    fn referenced_by(&self) -> Vec<Database> {
+        let mut holders = Vec::new();
+        if self.rust_refs > 0 {
+            holders.push(Database::Rust);
+        }
+        if self.c_refs > 0 {
+            holders.push(Database::C);
+        }
+        holders
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Problem {
+    DoubleFree { user: Allocation, call_site: String },
+    FreedWhileReferenced { user: Allocation, by: Vec<Database> },
+}
+
+struct Registry {
+    day: i32,
+    live: HashMap<usize, Allocation>,
+    // freed records, until their address is allocated again
+    freed: HashMap<usize, Allocation>,
+    rust_allocations: usize,
+    c_allocations: usize,
+    frees: usize,
+    problems: Vec<Problem>,
+}
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+fn registry() -> MutexGuard<'static, Option<Registry>> {
+    REGISTRY.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+// runs f when the registry is enabled
+fn with_registry<R>(f: impl FnOnce(&mut Registry) -> R) -> Option<R> {
+    registry().as_mut().map(f)
+}
+
+/// Start recording, before the first user is allocated
+pub fn enable() {
+    *registry() = Some(Registry {
+        day: 0,
+        live: HashMap::new(),
+        freed: HashMap::new(),
+        rust_allocations: 0,
+        c_allocations: 0,
+        frees: 0,
+        problems: Vec::new(),
+    });
+}
+
+pub fn is_enabled() -> bool {
+    registry().is_some()
+}
+
+// the day new allocations are dated with
+pub fn set_day(day: i32) {
+    with_registry(|r| r.day = day);
+}
+
+// user is a record that was just allocated
+pub fn allocated(user: &UserStruct) {
+    with_registry(|r| {
+        let addr = user as *const UserStruct as usize;
+        match user.allocator {
+            AllocatorType::RUST_ALLOCATED => r.rust_allocations += 1,
+            AllocatorType::C_ALLOCATED => r.c_allocations += 1,
+        }
+        r.freed.remove(&addr);
+        r.live.insert(
+            addr,
+            Allocation {
+                username: user.username.as_str().to_string(),
+                user_id: user.user_id,
+                allocator: user.allocator,
+                day: r.day,
+                owner: user.ownership(),
+                rust_refs: 0,
+                c_refs: 0,
+            },
+        );
+    });
+}
+
+// db took a reference to the live record user
+pub fn aliased(user: &UserStruct, db: Database) {
+    with_registry(|r| {
+        if let Some(alloc) = r.live.get_mut(&(user as *const UserStruct as usize)) {
+            // C assigns the user_id when it adds a user without one
+            alloc.user_id = user.user_id;
+            match db {
+                Database::Rust => alloc.rust_refs += 1,
+                Database::C => alloc.c_refs += 1,
+            }
+        }
+    });
+}
+
+// db let go of its reference, user may be freed right after so it is not read
+pub fn unaliased(user: *const UserStruct, db: Database) {
+    with_registry(|r| {
+        if let Some(alloc) = r.live.get_mut(&(user as usize)) {
+            let refs = match db {
+                Database::Rust => &mut alloc.rust_refs,
+                Database::C => &mut alloc.c_refs,
+            };
+            *refs = (*refs - 1).max(0);
+        }
+    });
+}
+
+pub fn owner_changed(user: *const UserStruct, owner: OwnershipType) {
+    with_registry(|r| {
+        if let Some(alloc) = r.live.get_mut(&(user as usize)) {
+            alloc.owner = owner;
+        }
+    });
+}
+
+// true (and recorded as a double free) when user was already freed, the caller must not touch it
+pub fn check_freed(user: *const UserStruct, call_site: &str) -> bool {
+    let addr = user as usize;
+    with_registry(|r| {
+        if r.live.contains_key(&addr) {
+            return false;
+        }
+        match r.freed.get(&addr) {
+            Some(alloc) => {
+                log::error!("DOUBLE FREE OF USER {:?} AT {}", alloc.username, call_site);
+                let problem = Problem::DoubleFree {
+                    user: alloc.clone(),
+                    call_site: call_site.to_string(),
+                };
+                r.problems.push(problem);
+                true
+            }
+            None => false,
+        }
+    })
+    .unwrap_or(false)
+}
+
+// user is about to be freed (its last reference was released)
+pub fn freed(user: *const UserStruct) {
+    let addr = user as usize;
+    with_registry(|r| {
+        let alloc = match r.live.remove(&addr) {
+            Some(alloc) => alloc,
+            None => return,
+        };
+        r.frees += 1;
+        let holders = alloc.referenced_by();
+        if !holders.is_empty() {
+            r.problems.push(Problem::FreedWhileReferenced {
+                user: alloc.clone(),
+                by: holders,
+            });
+        }
+        r.freed.insert(addr, alloc);
+    });
+}
+
+// None when the registry was never enabled. only meaningful after the rust database is dropped
+pub fn report() -> Option<String> {
+    with_registry(|r| {
+        let mut live: Vec<&Allocation> = r.live.values().collect();
+        live.sort_by(|a, b| (a.user_id, &a.username).cmp(&(b.user_id, &b.username)));
+        let (held, leaks): (Vec<&Allocation>, Vec<&Allocation>) =
+            live.into_iter().partition(|alloc| alloc.c_refs > 0 && alloc.rust_refs == 0);
+
+        let mut out = format!(
+            "{} user allocations ({} by rust, {} by C), {} freed, {} still held by the C database\n",
+            r.rust_allocations + r.c_allocations,
+            r.rust_allocations,
+            r.c_allocations,
+            r.frees,
+            held.len()
+        );
+        out.push_str(&format!("leaks: {}\n", leaks.len()));
+        for alloc in &leaks {
+            let held_by = if alloc.rust_refs > 0 {
+                format!("{} rust reference(s) never dropped", alloc.rust_refs)
+            } else {
+                "not referenced by either database".to_string()
+            };
+            out.push_str(&format!("  {}, {}\n", alloc.describe(), held_by));
+        }
+
+        let double_frees: Vec<&Problem> = r
+            .problems
+            .iter()
+            .filter(|p| matches!(p, Problem::DoubleFree { .. }))
+            .collect();
+        out.push_str(&format!("double frees: {}\n", double_frees.len()));
+        for problem in double_frees {
+            if let Problem::DoubleFree { user, call_site } = problem {
+                out.push_str(&format!("  {}, freed again at {}\n", user.describe(), call_site));
+            }
+        }
+
+        let early_frees: Vec<&Problem> = r
+            .problems
+            .iter()
+            .filter(|p| matches!(p, Problem::FreedWhileReferenced { .. }))
+            .collect();
+        out.push_str(&format!("frees while referenced: {}\n", early_frees.len()));
+        for problem in early_frees {
+            if let Problem::FreedWhileReferenced { user, by } = problem {
+                let names: Vec<&str> = by.iter().map(|db| db.name()).collect();
+                let databases = if by.len() > 1 { "databases" } else { "database" };
+                out.push_str(&format!(
+                    "  {}, freed while the {} {} still referenced it\n",
+                    user.describe(),
+                    names.join(" and "),
+                    databases
+                ));
+            }
+        }
+        out
+    })
+}
+
+pub fn print_report() {
+    if let Some(report) = report() {
+        println!("\n==========================Allocation Report==========================");
+        print!("{}", report);
+    }
+}
+
+// the C side of the hooks, see database_enhanced.c. unaliased and check_freed only use the address,
+// so those two are safe to call with any pointer
+
+/// # Safety
+/// `user` is NULL or a user record C just allocated
+#[no_mangle]
+pub unsafe extern "C" fn alloc_registry_allocated(user: *const UserStruct) {
+    if let Some(user) = user.as_ref() {
+        allocated(user);
+    }
+}
+
+/// # Safety
+/// `user` is NULL or a live user record
+#[no_mangle]
+pub unsafe extern "C" fn alloc_registry_c_aliased(user: *const UserStruct) {
+    if let Some(user) = user.as_ref() {
+        aliased(user, Database::C);
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn alloc_registry_c_unaliased(user: *const UserStruct) {
+    unaliased(user, Database::C);
+}
+
+/// 1 when user was freed already, free_user then returns without reading it
+///
+/// # Safety
+/// `call_site` is NULL or a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn alloc_registry_check_freed(user: *const UserStruct, call_site: *const c_char) -> c_int {
+    let call_site = c_call_site(call_site);
+    check_freed(user, &call_site) as c_int
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    // database_fix_full, which is the crate root in one binary and a module in the other
+    use super::super::FixedStr;
+
+    // the registry is global, each test starts it over
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    // kept alive by the caller, so its address cannot be handed out again during the test
+    fn user(username: &str, user_id: i32) -> Box<UserStruct> {
+        let mut user = Box::<UserStruct>::default();
+        user.username = FixedStr::truncated(username);
+        user.user_id = user_id;
+        user
+    }
+
+    fn section<'r>(report: &'r str, title: &str) -> Vec<&'r str> {
+        report
+            .lines()
+            .skip_while(|line| !line.starts_with(title))
+            .skip(1)
+            .take_while(|line| line.starts_with("  "))
+            .collect()
+    }
+
+    #[test]
+    fn leaks() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        enable();
+        set_day(3);
+        let forgotten = user("forgotten", 9001);
+        allocated(&forgotten);
+        aliased(&forgotten, Database::Rust);
+        let orphan = user("orphan", 9002);
+        allocated(&orphan);
+        let held = user("held-by-c", 9003);
+        allocated(&held);
+        aliased(&held, Database::C);
+
+        let report = report().unwrap();
+        let leaks = section(&report, "leaks:");
+        assert!(
+            leaks.contains(&"  \"forgotten\" (id 9001) allocated by rust on day 3, owner RUST_OWNED, 1 rust reference(s) never dropped"),
+            "{}",
+            report
+        );
+        assert!(
+            leaks
+                .iter()
+                .any(|l| l.contains("\"orphan\"")
+                    && l.ends_with("not referenced by either database")),
+            "{}",
+            report
+        );
+        assert!(!report.contains("held-by-c"), "{}", report);
+    }
+
+    #[test]
+    fn double_free() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        enable();
+        let twice = user("twice", 9011);
+        let ptr = &*twice as *const UserStruct;
+        allocated(&twice);
+        assert!(!check_freed(ptr, "first free"));
+        freed(ptr);
+        assert!(check_freed(ptr, "second free"));
+
+        let report = report().unwrap();
+        let double_frees = section(&report, "double frees:");
+        assert_eq!(double_frees.len(), 1, "{}", report);
+        assert!(
+            double_frees[0].contains("\"twice\"")
+                && double_frees[0].ends_with("freed again at second free")
+        );
+        assert!(
+            !section(&report, "leaks:")
+                .iter()
+                .any(|l| l.contains("twice")),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn freed_while_referenced() {
+        let _lock = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        enable();
+        let early = user("early", 9021);
+        let ptr = &*early as *const UserStruct;
+        allocated(&early);
+        aliased(&early, Database::Rust);
+        aliased(&early, Database::C);
+        unaliased(ptr, Database::Rust);
+        owner_changed(ptr, OwnershipType::C_OWNED);
+        freed(ptr);
+
+        let report = report().unwrap();
+        let early_frees = section(&report, "frees while referenced:");
+        assert_eq!(
+            early_frees,
+            ["  \"early\" (id 9021) allocated by rust on day 0, owner C_OWNED, freed while the C database still referenced it"],
+            "{}",
+            report
+        );
+    }
+}
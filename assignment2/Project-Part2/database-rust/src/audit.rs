@@ -0,0 +1,191 @@
+/* ------audit.rs------
+ *
+ * append-only audit trail of account events, one JSON object per line:
+ *   {"day":3,"event":"login","user_id":7,"username":"alice","outcome":"success"}
+ *
+ * day is the simulation day the event belongs to and user_id is null when there is no user
+ * (a failed login for an unknown name, a compaction). there is one sink per process - the
+ * C backend reports its events through db_audit_event in database_wrapper.rs, which ends up here
+ *
+ * fields named like a secret (see SECRET_FIELDS) are always written as "[REDACTED]", so a
+ * password or token passed by mistake never reaches the file
+ *
+ * nothing is written until open() is called. replaying the write ahead log re-runs events that
+ * were already audited, so recovery holds a Suspend guard while it replays
+ */
+
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+const SECRET_FIELDS: &[&str] = &["password", "password_hash", "salt", "hash", "session_token", "token"];
+const REDACTED: &str = "[REDACTED]";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum AuditValue {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+}
+
+impl From<&str> for AuditValue {
+    fn from(s: &str) -> Self {
+        AuditValue::Str(s.to_string())
+    }
+}
+
+impl From<String> for AuditValue {
+    fn from(s: String) -> Self {
+        AuditValue::Str(s)
+    }
+}
+
+impl From<i32> for AuditValue {
+    fn from(n: i32) -> Self {
+        AuditValue::Int(n as i64)
+    }
+}
+
+impl From<usize> for AuditValue {
+    fn from(n: usize) -> Self {
+        AuditValue::Int(n as i64)
+    }
+}
+
+impl From<bool> for AuditValue {
+    fn from(b: bool) -> Self {
+        AuditValue::Bool(b)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditEvent {
+    pub day: i32,
+    pub event: String,
+    pub user_id: Option<i32>,
+    pub username: Option<String>,
+    pub fields: Vec<(String, AuditValue)>,
+}
+
+impl AuditEvent {
+    pub fn new(day: i32, event: &str) -> Self {
+        AuditEvent {
+            day,
+            event: event.to_string(),
+            user_id: None,
+            username: None,
+            fields: Vec::new(),
+        }
+    }
+
+    pub fn user(mut self, user_id: Option<i32>, username: &str) -> Self {
+        self.user_id = user_id;
+        self.username = Some(username.to_string());
+        self
+    }
+
+    pub fn field<V: Into<AuditValue>>(mut self, key: &str, value: V) -> Self {
+        self.fields.push((key.to_string(), value.into()));
+        self
+    }
+
+    pub fn to_json(&self) -> String {
+        let mut out = String::from("{");
+        out.push_str(&format!("\"day\":{},\"event\":", self.day));
+        push_json_str(&mut out, &self.event);
+        out.push_str(",\"user_id\":");
+        match self.user_id {
+            Some(id) => out.push_str(&id.to_string()),
+            None => out.push_str("null"),
+        }
+        if let Some(username) = &self.username {
+            out.push_str(",\"username\":");
+            push_json_str(&mut out, username);
+        }
+        for (key, value) in &self.fields {
+            out.push(',');
+            push_json_str(&mut out, key);
+            out.push(':');
+            if SECRET_FIELDS.contains(&key.to_ascii_lowercase().as_str()) {
+                push_json_str(&mut out, REDACTED);
+                continue;
+            }
+            match value {
+                AuditValue::Str(s) => push_json_str(&mut out, s),
+                AuditValue::Int(n) => out.push_str(&n.to_string()),
+                AuditValue::Bool(b) => out.push_str(if *b { "true" } else { "false" }),
+            }
+        }
+        out.push('}');
+        out
+    }
+}
+
+pub fn push_json_str(out: &mut String, s: &str) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+struct Sink {
+    file: Option<File>,
+    // number of live Suspend guards
+    suspended: usize,
+}
+
+static SINK: Mutex<Sink> = Mutex::new(Sink {
+    file: None,
+    suspended: 0,
+});
+
+fn sink() -> std::sync::MutexGuard<'static, Sink> {
+    // a panic while writing one line does not make the trail unusable
+    SINK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Start appending events to `path` (created if missing, never truncated)
+pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<()> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    sink().file = Some(file);
+    Ok(())
+}
+
+pub fn record(event: AuditEvent) {
+    let mut sink = sink();
+    if sink.suspended > 0 {
+        return;
+    }
+    if let Some(file) = sink.file.as_mut() {
+        let line = event.to_json() + "\n";
+        if let Err(e) = file.write_all(line.as_bytes()) {
+            log::error!("Failed to write audit event {}: {}", event.event, e);
+        }
+    }
+}
+
+// events are dropped while one of these is alive
+pub struct Suspend;
+
+impl Suspend {
+    pub fn new() -> Self {
+        sink().suspended += 1;
+        Suspend
+    }
+}
+
+impl Drop for Suspend {
+    fn drop(&mut self) {
+        sink().suspended -= 1;
+    }
+}
@@ -1,3 +1,12 @@
+use std::collections::HashMap;
+use std::ptr::NonNull;
+use zeroize::Zeroize;
+
+//this file is a bin root and a module of mixed_code_database, #[path] finds the file from either
+#[path = "alloc_registry.rs"]
+pub mod alloc_registry;
+use alloc_registry::Database;
+
 const MAX_USERS: usize = 1000;
 const MAX_NAME_LEN: usize = 50;
 const INACTIVITY_THRESHOLD: i32 = 5;
@@ -5,48 +14,901 @@ const MAX_EMAIL_LEN: usize = 50;
 const MAX_PASSWORD_LENGTH: usize = 100;
 const MAX_SESSION_TOKEN_LEN: usize = 32;
 
-#[derive(Debug, Clone, PartialEq)]
+//password hashing: PBKDF2-HMAC-SHA256 with a random per-user salt
+//the version is stored with every record so the scheme can be changed later without breaking old users
+//(the iterations are stored too, so records hashed with an older count still verify)
+pub const PASSWORD_HASH_VERSION: u32 = 1;
+#[cfg(not(test))]
+pub const PASSWORD_HASH_ITERATIONS: u32 = 600_000;
+//tests hash many passwords, a low count keeps them fast (the stored count still makes them verify)
+#[cfg(test)]
+pub const PASSWORD_HASH_ITERATIONS: u32 = 1_000;
+//records come back from snapshots, the write ahead log and C, a huge stored count must not stall a login
+pub const MAX_PASSWORD_HASH_ITERATIONS: u32 = 10_000_000;
+pub const PASSWORD_SALT_LEN: usize = 16;
+pub const PASSWORD_HASH_LEN: usize = 32;
+
+//same values as OwnershipType in database_enhanced.c (C_OWNED is 0 there)
+//changed only through transition_ownership / user_transition_ownership once a record exists
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(C)]
+#[allow(non_camel_case_types)] //variants are named like the C enum
 pub enum OwnershipType {
-    RUST_OWNED,
-    C_OWNED,
-    SHARED_RUST_PRIMARY,
-    SHARED_C_PRIMARY,
+    RUST_OWNED = 1,
+    C_OWNED = 0,
+    SHARED_RUST_PRIMARY = 2,
+    SHARED_C_PRIMARY = 3,
+}
+
+//which side allocated a record, only that side's allocator ever frees it (see user_release)
+//same values as AllocatorType in database_enhanced.c
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[repr(C)]
+#[allow(non_camel_case_types)] //variants are named like the C enum
+pub enum AllocatorType {
+    RUST_ALLOCATED = 0,
+    C_ALLOCATED = 1,
+}
+
+//stored instead of the plaintext password - same layout as PasswordHash_t in C
+#[derive(Clone, PartialEq)]
+#[repr(C)]
+pub struct PasswordHash {
+    pub version: u32,
+    pub iterations: u32,
+    pub salt: [u8; PASSWORD_SALT_LEN],
+    pub hash: [u8; PASSWORD_HASH_LEN],
+}
+
+impl PasswordHash {
+    //only records made by hash_password, never the empty default one
+    pub fn is_valid(&self) -> bool {
+        return self.version == PASSWORD_HASH_VERSION
+            && self.iterations != 0
+            && self.iterations <= MAX_PASSWORD_HASH_ITERATIONS;
+    }
+}
+
+//salt and hash are left out, only the parameters are worth seeing in a log
+impl std::fmt::Debug for PasswordHash {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f
+            .debug_struct("PasswordHash")
+            .field("version", &self.version)
+            .field("iterations", &self.iterations)
+            .finish_non_exhaustive();
+    }
+}
+
+impl Default for PasswordHash {
+    fn default() -> Self {
+        //version 0 is never produced by hash_password, so an empty record never verifies
+        PasswordHash {
+            version: 0,
+            iterations: 0,
+            salt: [0; PASSWORD_SALT_LEN],
+            hash: [0; PASSWORD_HASH_LEN],
+        }
+    }
+}
+
+//fixed size text field: always NUL terminated and always valid UTF-8 up to the NUL
+//repr(transparent) over [u8; N], so it has the same layout as a char[N] in C
+#[derive(Clone, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct FixedStr<const N: usize>([u8; N]);
+
+impl<const N: usize> FixedStr<N> {
+    //bytes available for text, one is kept for the NUL terminator
+    pub const CAPACITY: usize = N - 1;
+
+    pub fn new() -> Self {
+        return FixedStr([0; N]);
+    }
+
+    //longest prefix of src that fits, cut on a character boundary
+    pub fn truncated(src: &str) -> Self {
+        let mut end = src.len().min(Self::CAPACITY);
+        while !src.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut out = Self::new();
+        out.0[..end].copy_from_slice(&src.as_bytes()[..end]);
+        return out;
+    }
+
+    //lossless conversion: fails instead of cutting, as_str() gives back exactly src
+    pub fn try_from_str(field: &'static str, src: &str) -> Result<Self, DbError> {
+        if src.as_bytes().contains(&0) {
+            return Err(DbError::InvalidInput);
+        }
+        if src.len() > Self::CAPACITY {
+            return Err(DbError::FieldTooLong {
+                field,
+                max: Self::CAPACITY,
+            });
+        }
+        return Ok(Self::truncated(src));
+    }
+
+    //for bytes that did not come from a &str (snapshots, the C side): stops at the first NUL and
+    //drops a character that was split or is not UTF-8, instead of showing U+FFFD later
+    pub fn from_raw(raw: [u8; N]) -> Self {
+        let len = raw.iter().position(|&b| b == 0).unwrap_or(N).min(Self::CAPACITY);
+        let valid = match std::str::from_utf8(&raw[..len]) {
+            Ok(_) => len,
+            Err(e) => e.valid_up_to(),
+        };
+        let mut out = Self::new();
+        out.0[..valid].copy_from_slice(&raw[..valid]);
+        return out;
+    }
+
+    pub fn from_c_chars(src: &[std::os::raw::c_char; N]) -> Self {
+        return Self::from_raw(src.map(|c| c as u8));
+    }
+
+    pub fn to_c_chars(&self) -> [std::os::raw::c_char; N] {
+        return self.0.map(|b| b as std::os::raw::c_char);
+    }
+
+    pub fn as_ptr(&self) -> *const std::os::raw::c_char {
+        return self.0.as_ptr() as *const std::os::raw::c_char;
+    }
+
+    //the text without the NUL terminator
+    pub fn as_bytes(&self) -> &[u8] {
+        let len = self.0.iter().position(|&b| b == 0).unwrap_or(N);
+        return &self.0[..len];
+    }
+
+    pub fn as_str(&self) -> &str {
+        let bytes = self.as_bytes();
+        return match std::str::from_utf8(bytes) {
+            Ok(s) => s,
+            //only possible if C wrote into the buffer, keep the whole characters
+            Err(e) => std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or(""),
+        };
+    }
+
+    //every byte including the NUL padding, the on-disk and in-memory representation
+    pub fn raw(&self) -> &[u8; N] {
+        return &self.0;
+    }
+}
+
+impl<const N: usize> Default for FixedStr<N> {
+    fn default() -> Self {
+        return Self::new();
+    }
+}
+
+impl<const N: usize> std::fmt::Debug for FixedStr<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{:?}", self.as_str());
+    }
+}
+
+impl<const N: usize> std::fmt::Display for FixedStr<N> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.write_str(self.as_str());
+    }
+}
+
+//what Debug and Display print for a credential
+pub const REDACTED: &str = "***";
+
+//a plaintext password on its way to hash_password / verify_password_hash
+//never printed, and the buffer is overwritten with zeros when it is dropped
+#[derive(Clone, PartialEq)]
+pub struct Password(String);
+
+impl Password {
+    pub fn new(password: String) -> Self {
+        return Password(password);
+    }
+
+    //the only way to get at the text, so every use of it is easy to find
+    pub fn expose(&self) -> &str {
+        return &self.0;
+    }
+}
+
+impl From<String> for Password {
+    fn from(password: String) -> Self {
+        return Password(password);
+    }
+}
+
+impl From<&str> for Password {
+    fn from(password: &str) -> Self {
+        return Password(password.to_string());
+    }
+}
+
+impl std::fmt::Debug for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.write_str(REDACTED);
+    }
+}
+
+impl std::fmt::Display for Password {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.write_str(REDACTED);
+    }
+}
+
+impl Drop for Password {
+    fn drop(&mut self) {
+        self.0.zeroize();
+    }
+}
+
+//session token field of UserStruct: a FixedStr that is never printed and is wiped on drop
+//repr(transparent), so UserStruct keeps the same layout as UserStruct_t in C
+#[derive(Clone, PartialEq, Default)]
+#[repr(transparent)]
+pub struct SessionToken(FixedStr<MAX_SESSION_TOKEN_LEN>);
+
+impl SessionToken {
+    pub fn new() -> Self {
+        return SessionToken(FixedStr::new());
+    }
+
+    pub fn from_raw(raw: [u8; MAX_SESSION_TOKEN_LEN]) -> Self {
+        return SessionToken(FixedStr::from_raw(raw));
+    }
+
+    pub fn as_ptr(&self) -> *const std::os::raw::c_char {
+        return self.0.as_ptr();
+    }
+
+    pub fn expose(&self) -> &str {
+        return self.0.as_str();
+    }
+}
+
+impl std::fmt::Debug for SessionToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.write_str(REDACTED);
+    }
+}
+
+impl std::fmt::Display for SessionToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return f.write_str(REDACTED);
+    }
+}
+
+impl Drop for SessionToken {
+    fn drop(&mut self) {
+        //FixedStr is Copy and has no Drop of its own, so wipe the bytes in place
+        (self.0).0.zeroize();
+    }
 }
 
 #[derive(Debug, Clone)]
 #[repr(C)]
 pub struct UserStruct {
-    pub password: [u8; MAX_PASSWORD_LENGTH],
-    pub username: [u8; MAX_NAME_LEN],
+    pub password_hash: PasswordHash,
+    pub username: FixedStr<MAX_NAME_LEN>,
     pub user_id: i32,
-    pub email: [u8; MAX_EMAIL_LEN],
+    pub email: FixedStr<MAX_EMAIL_LEN>,
     pub inactivity_count: i32,
     pub is_active: i32,
-    pub session_token: [u8; MAX_SESSION_TOKEN_LEN],
-    pub ownership: OwnershipType,
+    pub session_token: SessionToken,
+    //private so nothing outside this file can overwrite it, see transition_ownership
+    ownership: OwnershipType,
+    //number of databases (rust or C) holding the record, only meaningful once it is allocated
+    pub ref_count: i32,
+    pub allocator: AllocatorType,
+    //given by the database at signup (see RetentionPolicy::role_at_signup), a rename never changes it
+    pub role: Role,
 }
 
 impl Default for UserStruct {
     fn default() -> Self {
         UserStruct {
-            password: [0; MAX_PASSWORD_LENGTH],
-            username: [0; MAX_NAME_LEN],
+            password_hash: PasswordHash::default(),
+            username: FixedStr::new(),
             user_id: 0,
-            email: [0; MAX_EMAIL_LEN],
+            email: FixedStr::new(),
             inactivity_count: 0,
             is_active: 0,
-            session_token: [0; MAX_SESSION_TOKEN_LEN],
+            session_token: SessionToken::new(),
             ownership: OwnershipType::RUST_OWNED,
+            ref_count: 0,
+            allocator: AllocatorType::RUST_ALLOCATED,
+            role: Role::Student,
+        }
+    }
+}
+
+impl UserStruct {
+    pub fn ownership(&self) -> OwnershipType {
+        return self.ownership;
+    }
+
+    //for a record being recreated (snapshot decode) with the ownership it was saved with
+    //not a transition: the record is a plain value that was never allocated, so no database holds it
+    //yet (ref_count is 0). anything already allocated goes through transition_ownership
+    #[allow(dead_code)] //only the mixed binary decodes snapshots
+    pub(crate) fn with_ownership(mut self, ownership: OwnershipType) -> Self {
+        debug_assert!(self.ref_count == 0, "with_ownership on an allocated record");
+        self.ownership = ownership;
+        return self;
+    }
+}
+
+//the legal ownership transitions, the one place that decides how ownership may change:
+//  RUST_OWNED -> SHARED_C_PRIMARY           a rust user is shared with the C database
+//  C_OWNED -> SHARED_RUST_PRIMARY           a C user is shared with the rust database
+//  SHARED_* -> RUST_OWNED                   the C database let go of it, rust still holds it
+//  SHARED_* -> C_OWNED                      the rust database let go of it, C still holds it
+pub fn ownership_transition_allowed(from: OwnershipType, to: OwnershipType) -> bool {
+    use OwnershipType::*;
+    return matches!(
+        (from, to),
+        (RUST_OWNED, SHARED_C_PRIMARY)
+            | (C_OWNED, SHARED_RUST_PRIMARY)
+            | (SHARED_RUST_PRIMARY | SHARED_C_PRIMARY, RUST_OWNED | C_OWNED)
+    );
+}
+
+//an ownership rule was broken: logged (and refused by the caller), or in a strict-ownership build
+//the process is aborted right there so the call site is still on the stack
+fn ownership_violation(user: &UserStruct, what: &str, call_site: &str) {
+    let message = format!(
+        "OWNERSHIP VIOLATION: {} FOR USER {:?} (ID {}) AT {}",
+        what, user.username, user.user_id, call_site
+    );
+    if cfg!(feature = "strict-ownership") {
+        eprintln!("[RUST] {}", message);
+        std::process::abort();
+    }
+    log::error!("{}", message);
+}
+
+fn set_ownership(user: &mut UserStruct, to: OwnershipType, call_site: &str) -> Result<(), DbError> {
+    let from = user.ownership;
+    if !ownership_transition_allowed(from, to) {
+        ownership_violation(user, &format!("ILLEGAL TRANSITION {:?} -> {:?}", from, to), call_site);
+        return Err(DbError::IllegalOwnershipTransition { from, to });
+    }
+    log::trace!("OWNERSHIP OF {:?}: {:?} -> {:?} AT {}", user.username, from, to, call_site);
+    user.ownership = to;
+    alloc_registry::owner_changed(user, to);
+    return Ok(());
+}
+
+//moves user to `to`, an illegal transition is refused (aborts in a strict-ownership build)
+//the diagnostic names the caller's file and line
+#[track_caller]
+pub fn transition_ownership(user: &mut UserStruct, to: OwnershipType) -> Result<(), DbError> {
+    return set_ownership(user, to, &std::panic::Location::caller().to_string());
+}
+
+/// transition_ownership for C, which passes `__func__` as the call site. Returns 0, or -1 when refused
+///
+/// # Safety
+/// `user` is NULL or a live user record (allocated by either side) that nothing else is accessing,
+/// `call_site` is NULL or a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn user_transition_ownership(
+    user: *mut UserStruct,
+    to: std::os::raw::c_int,
+    call_site: *const std::os::raw::c_char,
+) -> i32 {
+    if user.is_null() {
+        return -1;
+    }
+    let to = match to {
+        0 => OwnershipType::C_OWNED,
+        1 => OwnershipType::RUST_OWNED,
+        2 => OwnershipType::SHARED_RUST_PRIMARY,
+        3 => OwnershipType::SHARED_C_PRIMARY,
+        _ => return -1,
+    };
+    let call_site = c_call_site(call_site);
+    return match set_ownership(&mut *user, to, &call_site) {
+        Ok(()) => 0,
+        Err(_) => -1,
+    };
+}
+
+/// C may only let go of a user the C database holds (C_OWNED or shared), called by free_user.
+/// Returns 1 when it may, 0 when the release has to be skipped (aborts in a strict-ownership build)
+///
+/// # Safety
+/// `user` is NULL or a live user record, `call_site` is NULL or a NUL terminated string
+#[no_mangle]
+pub unsafe extern "C" fn user_check_c_release(
+    user: *mut UserStruct,
+    call_site: *const std::os::raw::c_char,
+) -> i32 {
+    if user.is_null() {
+        return 0;
+    }
+    if (*user).ownership == OwnershipType::RUST_OWNED {
+        let call_site = c_call_site(call_site);
+        ownership_violation(&*user, "FREE BY C OF A RUST OWNED USER", &call_site);
+        return 0;
+    }
+    return 1;
+}
+
+unsafe fn c_call_site(call_site: *const std::os::raw::c_char) -> String {
+    if call_site.is_null() {
+        return "C".to_string();
+    }
+    return format!("{} (C)", std::ffi::CStr::from_ptr(call_site).to_string_lossy());
+}
+
+//cross-language reference counting for user records. a record can be held by the rust database,
+//the C database or both (after a join), each holder owns one reference. C calls these directly
+//(its free_user is a release), rust holds its references through UserRecord
+
+/// Adds a reference to `user` for a new holder and returns the references it has now
+///
+/// # Safety
+/// `user` is NULL or a live user record (allocated by either side) that nothing else is accessing
+#[no_mangle]
+pub unsafe extern "C" fn user_retain(user: *mut UserStruct) -> i32 {
+    if user.is_null() {
+        return 0;
+    }
+    (*user).ref_count += 1;
+    log::trace!("RETAINED USER {:?} - REFERENCES: {}", (*user).username, (*user).ref_count);
+    return (*user).ref_count;
+}
+
+/// Drops one reference to `user` and returns the references left. At zero the record is wiped and
+/// freed by the allocator that made it: rust allocated records as the Box they were, C allocated
+/// ones with free like database_enhanced.c would
+///
+/// # Safety
+/// `user` is NULL or a live user record and the caller holds one of its references, which it must
+/// not use again. With the allocation registry on, a record that was already freed is also accepted
+/// (it is reported as a double free and not read)
+#[no_mangle]
+pub unsafe extern "C" fn user_release(user: *mut UserStruct) -> i32 {
+    if user.is_null() {
+        return 0;
+    }
+    //only known with the allocation registry on, the record is then left alone
+    if alloc_registry::check_freed(user, "user_release") {
+        return 0;
+    }
+    if (*user).ref_count <= 0 {
+        log::error!("RELEASE OF USER {:?} WITHOUT A REFERENCE - IGNORED", (*user).username);
+        return 0;
+    }
+    (*user).ref_count -= 1;
+    log::trace!("RELEASED USER {:?} - REFERENCES: {}", (*user).username, (*user).ref_count);
+    if (*user).ref_count > 0 {
+        return (*user).ref_count;
+    }
+    log::debug!("FREEING USER {:?} ({:?})", (*user).username, (*user).allocator);
+    alloc_registry::freed(user);
+    (*user).password_hash.salt.zeroize();
+    (*user).password_hash.hash.zeroize();
+    match (*user).allocator {
+        AllocatorType::RUST_ALLOCATED => drop(Box::from_raw(user)),
+        AllocatorType::C_ALLOCATED => {
+            //drop_in_place wipes the session token, the memory itself goes back to malloc
+            std::ptr::drop_in_place(user);
+            libc::free(user as *mut libc::c_void);
         }
     }
+    return 0;
+}
+
+//one reference to a user record, what the rust database holds instead of a UserRecord
+//(the record may be shared with the C backend, so dropping it is a user_release, not a free)
+pub struct UserRecord(NonNull<UserStruct>);
+
+impl UserRecord {
+    //a new rust allocated record, with the one reference this UserRecord holds
+    pub fn new(mut user: UserStruct) -> Self {
+        user.ref_count = 1;
+        user.allocator = AllocatorType::RUST_ALLOCATED;
+        let record = UserRecord(NonNull::from(Box::leak(Box::new(user))));
+        alloc_registry::allocated(&record);
+        alloc_registry::aliased(&record, Database::Rust);
+        return record;
+    }
+
+    /// Takes over a reference the caller already holds (like Box::from_raw)
+    ///
+    /// # Safety
+    /// `user` is a live record allocated by either side (so not NULL), the caller holds one of its
+    /// references and hands it to the UserRecord - it must not be released anywhere else
+    pub unsafe fn from_raw(user: *mut UserStruct) -> Self {
+        let record = UserRecord(NonNull::new(user).expect("[RUST] NULL USER RECORD"));
+        alloc_registry::aliased(&record, Database::Rust);
+        return record;
+    }
+
+    /// A new reference to a record that stays held by whoever holds it now
+    ///
+    /// # Safety
+    /// `user` is a live record allocated by either side (so not NULL) that nothing else is accessing
+    pub unsafe fn retain(user: *mut UserStruct) -> Self {
+        user_retain(user);
+        return UserRecord::from_raw(user);
+    }
+
+    //for handing the record to the C backend, which has to user_retain it to keep it
+    pub fn as_ptr(&self) -> *mut UserStruct {
+        return self.0.as_ptr();
+    }
+}
+
+impl std::ops::Deref for UserRecord {
+    type Target = UserStruct;
+
+    fn deref(&self) -> &UserStruct {
+        return unsafe { self.0.as_ref() };
+    }
+}
+
+impl std::ops::DerefMut for UserRecord {
+    fn deref_mut(&mut self) -> &mut UserStruct {
+        return unsafe { self.0.as_mut() };
+    }
+}
+
+//a copy is a new record of its own, never another reference to this one
+impl Clone for UserRecord {
+    fn clone(&self) -> Self {
+        return UserRecord::new((**self).clone());
+    }
+}
+
+impl std::fmt::Debug for UserRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return (**self).fmt(f);
+    }
+}
+
+impl Drop for UserRecord {
+    fn drop(&mut self) {
+        //holding a UserRecord is what makes rust an owner, so unlike C's free_user there is nothing
+        //to check - but a shared record that C keeps is C's alone from here on
+        let shared = matches!(
+            self.ownership,
+            OwnershipType::SHARED_RUST_PRIMARY | OwnershipType::SHARED_C_PRIMARY
+        );
+        if shared && self.ref_count > 1 {
+            let _ = set_ownership(self, OwnershipType::C_OWNED, "UserRecord::drop");
+        }
+        alloc_registry::unaliased(self.0.as_ptr(), Database::Rust);
+        //the reference this UserRecord held, nothing reads the record through it after this
+        unsafe {
+            user_release(self.0.as_ptr());
+        }
+    }
+}
+
+//every operation that can fail returns one of these instead of panicking or silently doing nothing
+#[derive(Debug, Clone, PartialEq)]
+pub enum DbError {
+    Full,                                            //no free slot left in users
+    NotFound,                                        //no user with that username/id
+    DuplicateUsername,                               //username already taken
+    DuplicateUserId(i32),                            //a record brought its own user_id and it is taken
+    FieldTooLong { field: &'static str, max: usize }, //input would not fit in the fixed size buffer
+    InvalidInput,                                    //empty or contains a NUL byte
+    IllegalOwnershipTransition { from: OwnershipType, to: OwnershipType }, //see ownership_transition_allowed
+    NoRandomness,                                    //the OS could not provide a password salt
+}
+
+impl std::fmt::Display for DbError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DbError::Full => write!(f, "database is full ({} users)", MAX_USERS),
+            DbError::NotFound => write!(f, "user not found"),
+            DbError::DuplicateUsername => write!(f, "username already exists"),
+            DbError::DuplicateUserId(id) => write!(f, "user id {} already exists", id),
+            DbError::FieldTooLong { field, max } => {
+                write!(f, "{} is too long (max {} bytes)", field, max)
+            }
+            DbError::InvalidInput => write!(f, "invalid input"),
+            DbError::IllegalOwnershipTransition { from, to } => {
+                write!(f, "illegal ownership transition {:?} -> {:?}", from, to)
+            }
+            DbError::NoRandomness => write!(f, "no randomness available for the password salt"),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+//hands out user ids, an id is never given out twice even after the user is removed
+//the C backend allocates from the same counter (it gets a pointer to next_user_id, like the day counter)
+#[derive(Debug)]
+#[repr(C)]
+pub struct IdAllocator {
+    pub next_user_id: i32,
+}
+
+impl Default for IdAllocator {
+    fn default() -> Self {
+        //0 is used as "no id yet" by create_user callers, so ids start at 1
+        IdAllocator { next_user_id: 1 }
+    }
+}
+
+impl IdAllocator {
+    pub fn allocate(&mut self) -> i32 {
+        let id = self.next_user_id;
+        self.next_user_id += 1;
+        return id;
+    }
+
+    //makes sure an id that was assigned elsewhere is never handed out again
+    pub fn reserve(&mut self, user_id: i32) {
+        if user_id >= self.next_user_id {
+            self.next_user_id = user_id + 1;
+        }
+    }
+}
+
+//what update_database_daily does with a user, the same value is returned to the C backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub enum RetentionDecision {
+    Keep = 0,
+    //kept but marked inactive (is_active = 0) until the next login
+    Deactivate = 1,
+    Remove = 2,
+}
+
+//decides from the days since the last login whether a user stays, both backends ask the same object
+//(the rust one directly, the C one through db_retention_decide) so they always remove the same users
+pub trait RetentionPolicy: std::fmt::Debug {
+    fn decide(&self, role: Role, inactivity_count: i32) -> RetentionDecision;
+
+    //the role stored in a new user, only asked once when the user signs up
+    fn role_at_signup(&self, _username: &str) -> Role {
+        return Role::Student;
+    }
+}
+
+//removes a user once they have been inactive for more than threshold days
+#[derive(Debug, Clone)]
+pub struct FixedThreshold {
+    pub threshold: i32,
+}
+
+impl Default for FixedThreshold {
+    fn default() -> Self {
+        return FixedThreshold {
+            threshold: INACTIVITY_THRESHOLD,
+        };
+    }
+}
+
+impl RetentionPolicy for FixedThreshold {
+    fn decide(&self, _role: Role, inactivity_count: i32) -> RetentionDecision {
+        if inactivity_count > self.threshold {
+            return RetentionDecision::Remove;
+        }
+        return RetentionDecision::Keep;
+    }
+}
+
+//same values as UserRole in database_enhanced.c
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[repr(C)]
+pub enum Role {
+    Student = 0,
+    Staff = 1,
+    Admin = 2,
+}
+
+impl Role {
+    pub fn from_i32(role: i32) -> Option<Role> {
+        return match role {
+            0 => Some(Role::Student),
+            1 => Some(Role::Staff),
+            2 => Some(Role::Admin),
+            _ => None,
+        };
+    }
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(role: &str) -> Result<Self, String> {
+        match role.trim().to_ascii_lowercase().as_str() {
+            "student" => return Ok(Role::Student),
+            "staff" => return Ok(Role::Staff),
+            "admin" => return Ok(Role::Admin),
+            _ => return Err(format!("unknown role {:?}", role.trim())),
+        }
+    }
+}
+
+//a threshold per role, roles without a threshold are never removed
+//roles only picks the role a user gets at signup (by username, case-insensitive, default_role otherwise),
+//after that the role stored in the user is used so renaming yourself cannot change it
+#[derive(Debug, Clone)]
+pub struct PerRoleThreshold {
+    pub roles: HashMap<String, Role>,
+    pub default_role: Role,
+    pub thresholds: HashMap<Role, i32>,
+}
+
+impl RetentionPolicy for PerRoleThreshold {
+    fn decide(&self, role: Role, inactivity_count: i32) -> RetentionDecision {
+        match self.thresholds.get(&role) {
+            Some(&threshold) if inactivity_count > threshold => return RetentionDecision::Remove,
+            _ => return RetentionDecision::Keep,
+        }
+    }
+
+    fn role_at_signup(&self, username: &str) -> Role {
+        return *self
+            .roles
+            .get(&normalize_username(username))
+            .unwrap_or(&self.default_role);
+    }
+}
+
+//past threshold a user is deactivated first, and only removed after grace_days more days without a login
+#[derive(Debug, Clone)]
+pub struct GracePeriod {
+    pub threshold: i32,
+    pub grace_days: i32,
+}
+
+impl RetentionPolicy for GracePeriod {
+    fn decide(&self, _role: Role, inactivity_count: i32) -> RetentionDecision {
+        if inactivity_count > self.threshold + self.grace_days {
+            return RetentionDecision::Remove;
+        }
+        if inactivity_count > self.threshold {
+            return RetentionDecision::Deactivate;
+        }
+        return RetentionDecision::Keep;
+    }
+}
+
+fn parse_days(days: &str) -> Result<i32, String> {
+    match days.trim().parse::<i32>() {
+        Ok(days) if days >= 0 => return Ok(days),
+        _ => return Err(format!("invalid number of days {:?}", days.trim())),
+    }
+}
+
+//a retention policy from its command line form (--retention in mixed_code_database.rs):
+//  fixed:<days>                                  FixedThreshold
+//  grace:<days>:<grace_days>                     GracePeriod
+//  roles:<role>=<days>,...[;<username>=<role>,...]  PerRoleThreshold
+//roles are student, staff and admin. unlisted users are students unless *=<role> is given,
+//a role without days is never removed
+pub fn parse_retention_policy(spec: &str) -> Result<Box<dyn RetentionPolicy>, String> {
+    let (kind, args) = spec.split_once(':').unwrap_or((spec, ""));
+    match kind.trim() {
+        "fixed" => {
+            return Ok(Box::new(FixedThreshold {
+                threshold: parse_days(args)?,
+            }));
+        }
+        "grace" => {
+            let (threshold, grace_days) = args
+                .split_once(':')
+                .ok_or_else(|| format!("expected grace:<days>:<grace_days>, got {:?}", spec))?;
+            return Ok(Box::new(GracePeriod {
+                threshold: parse_days(threshold)?,
+                grace_days: parse_days(grace_days)?,
+            }));
+        }
+        "roles" => {
+            let (thresholds, users) = args.split_once(';').unwrap_or((args, ""));
+            let mut policy = PerRoleThreshold {
+                roles: HashMap::new(),
+                default_role: Role::Student,
+                thresholds: HashMap::new(),
+            };
+            for entry in thresholds.split(',').filter(|e| !e.trim().is_empty()) {
+                let (role, days) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected <role>=<days>, got {:?}", entry))?;
+                policy.thresholds.insert(role.parse()?, parse_days(days)?);
+            }
+            for entry in users.split(',').filter(|e| !e.trim().is_empty()) {
+                let (username, role) = entry
+                    .split_once('=')
+                    .ok_or_else(|| format!("expected <username>=<role>, got {:?}", entry))?;
+                match username.trim() {
+                    "*" => policy.default_role = role.parse()?,
+                    username => {
+                        policy.roles.insert(normalize_username(username), role.parse()?);
+                    }
+                }
+            }
+            return Ok(Box::new(policy));
+        }
+        _ => return Err(format!("unknown retention policy {:?}", spec)),
+    }
+}
+
+//why a user was moved to the archive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RemovalReason {
+    //removed by the retention policy after this many days without a login
+    Inactivity { days: i32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tombstone {
+    pub removed_on_day: i32,
+    pub reason: RemovalReason,
+}
+
+//a removed user, kept so they can be restored with the same user_id and ownership
+//C_OWNED records were removed by the C backend (it frees its own copy), everything else by rust
+#[derive(Debug, Clone)]
+pub struct ArchivedUser {
+    pub user: UserRecord,
+    pub tombstone: Tombstone,
 }
 
 #[derive(Debug)]
 pub struct UserDatabase {
-    pub users: [Option<Box<UserStruct>>; MAX_USERS],
+    pub users: [Option<UserRecord>; MAX_USERS],
     pub count: i32,
     pub capacity: i32,
+    pub id_allocator: IdAllocator,
+    //called with each user update_database_daily moved to the archive, so anything else still
+    //pointing at the allocation (the C backend after a join) can forget it
+    pub on_user_removed: Option<fn(&ArchivedUser)>,
+    //decides who update_database_daily removes, FixedThreshold(INACTIVITY_THRESHOLD) unless replaced
+    pub retention_policy: Box<dyn RetentionPolicy>,
+    //the day update_database_daily last ran for, tombstones are dated with it
+    pub day: i32,
+    //users removed by either backend, oldest first
+    pub archive: Vec<ArchivedUser>,
+    //indices of empty slots in users, add_user takes from here and removals give the slot back
+    //(count is only the number of live users, it says nothing about where they are)
+    free_slots: Vec<usize>,
+    //lookup tables into users, kept in sync by add_user, update_username and update_database_daily
+    //usernames are stored normalized (see normalize_username) so lookups follow one set of rules
+    username_index: HashMap<String, usize>,
+    id_index: HashMap<i32, usize>,
+}
+
+//the live users in slot order, without the UserRecord of each slot
+pub struct Users<'a> {
+    slots: std::slice::Iter<'a, Option<UserRecord>>,
+}
+
+impl<'a> Iterator for Users<'a> {
+    type Item = &'a UserStruct;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        return self.slots.by_ref().find_map(|slot| slot.as_deref());
+    }
+}
+
+impl UserDatabase {
+    pub fn iter(&self) -> Users<'_> {
+        return Users {
+            slots: self.users.iter(),
+        };
+    }
+}
+
+impl<'a> IntoIterator for &'a UserDatabase {
+    type Item = &'a UserStruct;
+    type IntoIter = Users<'a>;
+
+    fn into_iter(self) -> Users<'a> {
+        return self.iter();
+    }
 }
 
 pub fn init_database() -> Box<UserDatabase> {
@@ -54,147 +916,249 @@ pub fn init_database() -> Box<UserDatabase> {
         users: std::array::from_fn(|_| None),
         count: 0,
         capacity: MAX_USERS as i32,
+        id_allocator: IdAllocator::default(),
+        on_user_removed: None,
+        retention_policy: Box::new(FixedThreshold::default()),
+        day: 0,
+        archive: Vec::new(),
+        //reversed so the lowest slot is handed out first
+        free_slots: (0..MAX_USERS).rev().collect(),
+        username_index: HashMap::new(),
+        id_index: HashMap::new(),
     };
     return Box::new(db);
 }
 
-pub fn add_user(db: &mut UserDatabase, mut user: Box<UserStruct>) {
-    println!("[RUST] ADD_USER()");
-    (*user).user_id = (*db).count + 1;
-    println!("[RUST] USER_ID: {:?}", (*user).user_id);
-    let index: usize = (*db).count as usize;
+//usernames are compared case-insensitively (ascii), this is the single place that decides that
+pub fn normalize_username(username: &str) -> String {
+    return username.to_ascii_lowercase();
+}
 
-    println!("[RUST] CURRENT DB COUNT: {:?}", db.count);
-    println!("[RUST] MAX USERS: {}", MAX_USERS);
+fn username_key(user: &UserStruct) -> String {
+    return normalize_username(user.username.as_str());
+}
+
+fn index_user(db: &mut UserDatabase, index: usize) {
+    if let Some(user) = &db.users[index] {
+        let key = username_key(user);
+        let id = user.user_id;
+        db.username_index.insert(key, index);
+        db.id_index.insert(id, index);
+    }
+}
+
+fn unindex_user(db: &mut UserDatabase, index: usize) {
+    if let Some(user) = &db.users[index] {
+        let key = username_key(user);
+        let id = user.user_id;
+        //only remove entries that still point at this slot
+        if db.username_index.get(&key) == Some(&index) {
+            db.username_index.remove(&key);
+        }
+        if db.id_index.get(&id) == Some(&index) {
+            db.id_index.remove(&id);
+        }
+    }
+}
+
+pub fn add_user(db: &mut UserDatabase, mut user: UserRecord) -> Result<(), DbError> {
+    log::trace!("ADD_USER()");
+    if db.count as usize >= MAX_USERS {
+        log::warn!("DB FULL, CANNOT ADD USER");
+        return Err(DbError::Full);
+    }
+    if db.username_index.contains_key(&username_key(&user)) {
+        log::warn!("USERNAME {:?} ALREADY EXISTS", user.username);
+        return Err(DbError::DuplicateUsername);
+    }
+    //users shared from the C backend already have an id from the same allocator, keep it
+    //(C knows them by that id, so a clash is an error rather than a reason to hand out a new one)
+    if user.user_id <= 0 {
+        user.user_id = db.id_allocator.allocate();
+    } else if db.id_index.contains_key(&user.user_id) {
+        log::warn!("USER ID {} ALREADY EXISTS", user.user_id);
+        return Err(DbError::DuplicateUserId(user.user_id));
+    } else {
+        db.id_allocator.reserve(user.user_id);
+    }
+    log::trace!("USER_ID: {:?}", user.user_id);
+    let index: usize = match db.free_slots.pop() {
+        Some(index) => index,
+        None => return Err(DbError::Full),
+    };
+
+    log::trace!("CURRENT DB COUNT: {:?}", db.count);
+    log::trace!("MAX USERS: {}", MAX_USERS);
     (*db).users[index] = Some(user);
-    println!(
-        "[RUST] USER: {:?} ADDED TO DB AT INDEX: {:?}",
+    index_user(db, index);
+    log::debug!(
+        "USER: {:?} ADDED TO DB AT INDEX: {:?}",
         (*db).users[index],
         index
     );
     (*db).count += 1;
+    return Ok(());
 }
 
-fn copy_string(dest: &mut [u8], src: &str, n: usize) {
-    //gets the minimum between src.len and n
-    let copy_len = src.len().min(n);
-    for i in 0..copy_len {
-        dest[i] = src.as_bytes()[i];
+fn derive_key(password: &str, salt: &[u8; PASSWORD_SALT_LEN], iterations: u32) -> [u8; PASSWORD_HASH_LEN] {
+    let mut out = [0u8; PASSWORD_HASH_LEN];
+    pbkdf2::pbkdf2_hmac::<sha2::Sha256>(
+        password.as_bytes(),
+        salt,
+        iterations,
+        &mut out,
+    );
+    return out;
+}
+
+//fails only when the OS has no randomness to give, C reaches this through db_hash_password so it must not panic
+pub fn hash_password(password: &Password) -> Result<PasswordHash, DbError> {
+    let mut salt = [0u8; PASSWORD_SALT_LEN];
+    if let Err(e) = getrandom::getrandom(&mut salt) {
+        log::error!("NO RANDOMNESS AVAILABLE FOR PASSWORD SALT: {}", e);
+        return Err(DbError::NoRandomness);
     }
-    if copy_len < dest.len() {
-        dest[copy_len] = 0;
+    let hash = derive_key(password.expose(), &salt, PASSWORD_HASH_ITERATIONS);
+    return Ok(PasswordHash {
+        version: PASSWORD_HASH_VERSION,
+        iterations: PASSWORD_HASH_ITERATIONS,
+        salt,
+        hash,
+    });
+}
+
+//constant time comparison so the time taken doesnt leak how many bytes matched
+//a candidate over the length limit can never have been stored, so it fails without being hashed
+//and a record over MAX_PASSWORD_HASH_ITERATIONS is not valid, so it is never hashed either
+pub fn verify_password_hash(record: &PasswordHash, candidate: &Password) -> bool {
+    if !record.is_valid() || candidate.expose().len() > MAX_PASSWORD_LENGTH - 1 {
+        return false;
     }
+    let mut computed = derive_key(candidate.expose(), &record.salt, record.iterations);
+    let mut diff = 0u8;
+    for (a, b) in computed.iter().zip(record.hash.iter()) {
+        diff |= a ^ b;
+    }
+    //a correct candidate produces the stored hash, so it is as sensitive as the record
+    computed.zeroize();
+    return diff == 0;
 }
 
-pub fn create_user(username: &str, email: &str, user_id: i32, password: &str) -> Box<UserStruct> {
-    let mut user = UserStruct {
-        username: [0; MAX_NAME_LEN],
-        email: [0; MAX_EMAIL_LEN],
-        password: [0; MAX_PASSWORD_LENGTH],
-        user_id: user_id,
+pub fn verify_password(db: &UserDatabase, username: &str, candidate: &Password) -> bool {
+    match find_user_by_username(db, username) {
+        Some(user) => verify_password_hash(&user.password_hash, candidate),
+        None => false,
+    }
+}
+
+//used when the password was hashed earlier (pending signups and write ahead log replay)
+pub fn create_user_with_hash(
+    username: &str,
+    email: &str,
+    user_id: i32,
+    password_hash: PasswordHash,
+) -> UserRecord {
+    let user = UserStruct {
+        username: FixedStr::truncated(username),
+        email: FixedStr::truncated(email),
+        password_hash,
+        user_id,
         inactivity_count: 0,
         is_active: 1,
-        session_token: [0; MAX_SESSION_TOKEN_LEN],
+        session_token: SessionToken::new(),
         ownership: OwnershipType::RUST_OWNED,
+        //set by UserRecord::new
+        ref_count: 0,
+        allocator: AllocatorType::RUST_ALLOCATED,
+        //set by the database that signs the user up
+        role: Role::Student,
     };
 
-    copy_string(&mut user.username, username, MAX_NAME_LEN);
-    copy_string(&mut user.email, email, MAX_EMAIL_LEN);
-    copy_string(&mut user.password, password, MAX_PASSWORD_LENGTH);
-
-    println!("[RUST] USER CREATED: {:?}", user.username);
+    log::debug!("USER CREATED: {:?}", user.username);
 
-    return Box::new(user);
+    return UserRecord::new(user);
 }
 
-fn get_nullt_index_from_u8(bytes: &[u8]) -> usize {
-    for i in 0..bytes.len() {
-        if bytes[i] == 0 {
-            return i;
-        }
+//checks that src fits in a buffer of size n (leaving room for the null terminator)
+fn check_field(field: &'static str, src: &str, n: usize) -> Result<(), DbError> {
+    if src.is_empty() || src.as_bytes().contains(&0) {
+        return Err(DbError::InvalidInput);
     }
-    return bytes.len();
-}
-
-//from u8 to string, excluding null terminator
-fn u8_to_string_no_nullt(bytes: &[u8]) -> String {
-    let null_idx: usize = get_nullt_index_from_u8(bytes);
-    return String::from_utf8_lossy(&bytes[0..null_idx]).to_string();
+    if src.len() > n - 1 {
+        return Err(DbError::FieldTooLong { field, max: n - 1 });
+    }
+    return Ok(());
 }
 
 pub fn find_user_by_username<'a>(
     db: &'a UserDatabase,
     username: &'a str,
 ) -> Option<&'a UserStruct> {
-    for user in &(*db).users {
-        if let Some(_user) = user {
-            let null_idx = get_nullt_index_from_u8(&_user.username);
-            if username
-                .as_bytes()
-                .eq_ignore_ascii_case(&_user.username[0..null_idx])
-            {
-                return Some(_user);
-            }
-        }
-    }
-    return None;
+    let index = *db.username_index.get(&normalize_username(username))?;
+    return db.users[index].as_deref();
 }
 
 pub fn find_user_by_username_mut<'a>(
     db: &'a mut UserDatabase,
     username: &'a str,
 ) -> Option<&'a mut UserStruct> {
-    for user in &mut (*db).users {
-        if let Some(_user) = user {
-            let null_idx = get_nullt_index_from_u8(&_user.username);
-            if username
-                .as_bytes()
-                .eq_ignore_ascii_case(&_user.username[0..null_idx])
-            {
-                return Some(_user);
-            }
-        }
-    }
-    return None;
+    let index = *db.username_index.get(&normalize_username(username))?;
+    return db.users[index].as_deref_mut();
 }
 
-pub fn print_user(user: &Box<UserStruct>) {
-    let username = u8_to_string_no_nullt(&user.username);
-    let email = u8_to_string_no_nullt(&user.email);
-    let password = u8_to_string_no_nullt(&user.password);
-
+pub fn print_user(user: &UserRecord) {
     println!(
-        "User: {:?}, ID: {:?} Email: {:?}, Inactivity: {:?}, Password: {:?}",
-        username, user.user_id, email, user.inactivity_count, password
+        "User: {:?}, ID: {:?} Email: {:?}, Inactivity: {:?}",
+        user.username, user.user_id, user.email, user.inactivity_count
     );
 }
 
+//walks the occupied slots of users (users can sit anywhere in the array, not just below count)
+pub fn iter_users(db: &UserDatabase) -> impl Iterator<Item = &UserRecord> {
+    return db.users.iter().filter_map(|user| user.as_ref());
+}
+
 pub fn print_database(db: &UserDatabase) {
-    for user in &(*db).users {
-        if let Some(_user) = user {
-            print_user(_user);
-        }
+    for user in iter_users(db) {
+        print_user(user);
     }
 }
 
+//ages every user by a day and applies the retention policy. users shared with the C backend are
+//aged here only, the C update_database_daily skips them (it would count the same day twice)
 pub fn update_database_daily(db: &mut UserDatabase) {
     let mut user_removed = 0;
-    println!("[RUST] UPDATE_DATABASE_DAILY()");
-    for i in 0..(*db).count as usize {
+    log::trace!("UPDATE_DATABASE_DAILY()");
+    db.day += 1;
+    for i in 0..MAX_USERS {
+        unindex_user(db, i);
         if let Some(mut _user) = (*db).users[i].take() {
-            println!(
-                "[RUST] CURRENT USER INACTIVITY COUNT: {:?}",
+            log::trace!(
+                "CURRENT USER INACTIVITY COUNT: {:?}",
                 _user.inactivity_count
             );
-            if _user.inactivity_count > INACTIVITY_THRESHOLD {
-                println!(
-                    "[RUST] USER: {:?} HAS BEEN INACTIVE, REMOVING",
+            let decision = db
+                .retention_policy
+                .decide(_user.role, _user.inactivity_count);
+            if decision == RetentionDecision::Remove {
+                log::info!(
+                    "USER: {:?} HAS BEEN INACTIVE, REMOVING",
                     _user.user_id
                 );
+                let days = _user.inactivity_count;
+                archive_user(db, _user, RemovalReason::Inactivity { days });
+                if let (Some(hook), Some(archived)) = (db.on_user_removed, db.archive.last()) {
+                    hook(archived);
+                }
+                db.free_slots.push(i);
                 user_removed += 1;
             } else {
+                if decision == RetentionDecision::Deactivate {
+                    _user.is_active = 0;
+                }
                 _user.inactivity_count += 1;
                 db.users[i] = Some(_user);
+                index_user(db, i);
             }
         }
     }
@@ -202,13 +1166,219 @@ pub fn update_database_daily(db: &mut UserDatabase) {
     db.count -= user_removed;
 }
 
-pub fn user_login(db: &mut UserDatabase, username: &str) {
+pub fn archive_user(db: &mut UserDatabase, user: UserRecord, reason: RemovalReason) {
+    log::debug!("ARCHIVING USER: {:?} ({:?})", user.username, reason);
+    let tombstone = Tombstone {
+        removed_on_day: db.day,
+        reason,
+    };
+    db.archive.push(ArchivedUser { user, tombstone });
+}
+
+//the most recently archived user with that name
+pub fn find_archived_user<'a>(db: &'a UserDatabase, username: &str) -> Option<&'a ArchivedUser> {
+    let key = normalize_username(username);
+    return db
+        .archive
+        .iter()
+        .rev()
+        .find(|archived| username_key(&archived.user) == key);
+}
+
+pub fn take_archived_user(db: &mut UserDatabase, username: &str) -> Option<ArchivedUser> {
+    let key = normalize_username(username);
+    let index = db
+        .archive
+        .iter()
+        .rposition(|archived| username_key(&archived.user) == key)?;
+    return Some(db.archive.remove(index));
+}
+
+//puts an archived user back with their user_id and ownership, as if they had just logged in
+pub fn restore_user(db: &mut UserDatabase, username: &str) -> Result<(), DbError> {
+    if find_archived_user(db, username).is_none() {
+        return Err(DbError::NotFound);
+    }
+    if db.username_index.contains_key(&normalize_username(username)) {
+        return Err(DbError::DuplicateUsername);
+    }
+    if db.count as usize >= MAX_USERS {
+        return Err(DbError::Full);
+    }
+    let mut user = match take_archived_user(db, username) {
+        Some(archived) => archived.user,
+        None => return Err(DbError::NotFound),
+    };
+    user.inactivity_count = 0;
+    user.is_active = 1;
+    return add_user(db, user);
+}
+
+pub fn user_login(db: &mut UserDatabase, username: &str) -> Result<(), DbError> {
     let user = find_user_by_username_mut(db, username);
     if let Some(_user) = user {
         _user.inactivity_count = 0;
+        _user.is_active = 1;
+        return Ok(());
+    }
+    return Err(DbError::NotFound);
+}
+
+pub fn update_username(
+    db: &mut UserDatabase,
+    username: &str,
+    new_username: &str,
+) -> Result<(), DbError> {
+    check_field("username", new_username, MAX_NAME_LEN)?;
+    let new_name = FixedStr::try_from_str("username", new_username)?;
+    let index = match db.username_index.get(&normalize_username(username)) {
+        Some(&index) => index,
+        None => return Err(DbError::NotFound),
+    };
+    //renaming to the same name (or a different casing of it) is fine
+    if let Some(&other) = db.username_index.get(&normalize_username(new_username)) {
+        if other != index {
+            return Err(DbError::DuplicateUsername);
+        }
+    }
+    unindex_user(db, index);
+    if let Some(_user) = db.users[index].as_deref_mut() {
+        _user.username = new_name;
     }
+    index_user(db, index);
+    return Ok(());
+}
+
+//the new password is checked against the PasswordPolicy and hashed by the caller
+//(EnhancedStudentDatabase::update_password, and write ahead log replay with the logged hash)
+pub fn update_password_hash(
+    db: &mut UserDatabase,
+    username: &str,
+    password_hash: PasswordHash,
+) -> Result<(), DbError> {
+    let user = find_user_by_username_mut(db, username);
+    if let Some(_user) = user {
+        _user.password_hash = password_hash;
+        return Ok(());
+    }
+    return Err(DbError::NotFound);
 }
 
 fn main() {
     print!("Hello, world!");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_threshold_removes_after_threshold() {
+        let policy = FixedThreshold { threshold: 5 };
+        for (days, expected) in [
+            (0, RetentionDecision::Keep),
+            (5, RetentionDecision::Keep),
+            (6, RetentionDecision::Remove),
+        ] {
+            assert_eq!(policy.decide(Role::Student, days), expected, "{} days", days);
+        }
+    }
+
+    #[test]
+    fn grace_period_deactivates_before_removing() {
+        let policy = GracePeriod {
+            threshold: 3,
+            grace_days: 2,
+        };
+        for (days, expected) in [
+            (3, RetentionDecision::Keep),
+            (4, RetentionDecision::Deactivate),
+            (5, RetentionDecision::Deactivate),
+            (6, RetentionDecision::Remove),
+        ] {
+            assert_eq!(policy.decide(Role::Student, days), expected, "{} days", days);
+        }
+    }
+
+    #[test]
+    fn per_role_threshold_uses_the_role_of_each_user() {
+        let policy = parse_retention_policy("roles:student=2,staff=10;Alice=staff,root=admin").unwrap();
+        for (username, role) in [("bob", Role::Student), ("ALICE", Role::Staff), ("root", Role::Admin)] {
+            assert_eq!(policy.role_at_signup(username), role, "{}", username);
+        }
+        for (role, days, expected) in [
+            (Role::Student, 3, RetentionDecision::Remove),
+            (Role::Staff, 3, RetentionDecision::Keep),
+            (Role::Staff, 11, RetentionDecision::Remove),
+            //admin has no threshold
+            (Role::Admin, 1000, RetentionDecision::Keep),
+        ] {
+            assert_eq!(policy.decide(role, days), expected, "{:?} after {} days", role, days);
+        }
+    }
+
+    #[test]
+    fn per_role_default_role() {
+        let policy = parse_retention_policy("roles:staff=1;*=staff").unwrap();
+        assert_eq!(policy.role_at_signup("anyone"), Role::Staff);
+        assert_eq!(policy.decide(Role::Staff, 2), RetentionDecision::Remove);
+    }
+
+    #[test]
+    fn a_taken_user_id_is_an_error() {
+        let mut db = init_database();
+        add_user(&mut db, create_user_with_hash("alice", "alice@nus.edu.sg", 0, PasswordHash::default())).unwrap();
+        let alice_id = find_user_by_username(&db, "alice").unwrap().user_id;
+        //a shared record keeps the id C knows it by, it is never given a new one
+        let clash = create_user_with_hash("bob", "bob@nus.edu.sg", alice_id, PasswordHash::default());
+        assert_eq!(add_user(&mut db, clash), Err(DbError::DuplicateUserId(alice_id)));
+        assert!(find_user_by_username(&db, "bob").is_none());
+    }
+
+    #[test]
+    fn renaming_does_not_change_the_role() {
+        let mut db = init_database();
+        db.retention_policy = parse_retention_policy("roles:student=1;root=admin").unwrap();
+        let mut user = create_user_with_hash("mallory", "mallory@nus.edu.sg", 0, PasswordHash::default());
+        user.role = db.retention_policy.role_at_signup("mallory");
+        add_user(&mut db, user).unwrap();
+        //root is free, so the rename goes through
+        update_username(&mut db, "mallory", "root").unwrap();
+        assert_eq!(find_user_by_username(&db, "root").map(|user| user.role), Some(Role::Student));
+        for _ in 0..3 {
+            update_database_daily(&mut db);
+        }
+        assert!(find_user_by_username(&db, "root").is_none());
+    }
+
+    #[test]
+    fn parse_retention_policy_accepts() {
+        for (spec, days, expected) in [
+            ("fixed:5", 6, RetentionDecision::Remove),
+            ("fixed: 7", 6, RetentionDecision::Keep),
+            ("grace:1:1", 2, RetentionDecision::Deactivate),
+            ("roles:student=0", 1, RetentionDecision::Remove),
+        ] {
+            let policy = parse_retention_policy(spec).unwrap_or_else(|e| panic!("{}: {}", spec, e));
+            assert_eq!(policy.decide(Role::Student, days), expected, "{}", spec);
+        }
+    }
+
+    #[test]
+    fn parse_retention_policy_rejects() {
+        for spec in [
+            "",
+            "fixed",
+            "fixed:-1",
+            "fixed:five",
+            "grace:3",
+            "roles:teacher=3",
+            "roles:student",
+            "roles:student=3;alice",
+            "roles:student=3;alice=root",
+            "forever:1",
+        ] {
+            assert!(parse_retention_policy(spec).is_err(), "{:?} was accepted", spec);
+        }
+    }
+}
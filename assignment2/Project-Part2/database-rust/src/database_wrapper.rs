@@ -1,27 +1,37 @@
-use crate::database_fix_full::OwnershipType;
-use crate::database_fix_full::{UserDatabase, UserStruct};
-use crate::{MAX_PASSWORD_LENGTH, SESSION_TOKEN_MAX_LEN};
+use crate::database_fix_full::{user_release, user_retain, AllocatorType, OwnershipType, UserRecord};
+use crate::database_fix_full::{hash_password, verify_password_hash, PasswordHash};
+use crate::database_fix_full::{FixedStr, IdAllocator, UserDatabase, UserStruct};
+use crate::database_fix_full::{Password, SessionToken};
+use crate::database_fix_full::{archive_user, FixedThreshold, RemovalReason};
+use crate::database_fix_full::{DbError, RetentionDecision, RetentionPolicy, Role};
+use crate::database_fix_full::transition_ownership;
+use crate::audit::{self, AuditEvent};
+use crate::logging;
+use crate::password_policy;
 use std::ffi::{CStr, CString};
-use std::os::raw::{c_char, c_int};
-use std::usize::MAX;
+use std::os::raw::{c_char, c_int, c_void};
+use zeroize::Zeroize;
 
 const MAX_USERS: usize = 1000;
 const MAX_NAME_LEN: usize = 50;
-const INACTIVITY_THRESHOLD: i32 = 5;
 const MAX_EMAIL_LEN: usize = 50;
 const MAX_SESSION_TOKEN_LEN: usize = 32;
 
 // C struct representations
 #[repr(C)]
 pub struct UserStructT {
-    pub password: [c_char; MAX_PASSWORD_LENGTH],
-    pub username: [c_char; MAX_NAME_LEN],
+    pub password_hash: PasswordHash,
+    pub username: FixedStr<MAX_NAME_LEN>,
     pub user_id: c_int,
-    pub email: [c_char; MAX_EMAIL_LEN],
+    pub email: FixedStr<MAX_EMAIL_LEN>,
     pub inactivity_count: c_int,
     pub is_active: c_int,
-    pub session_token: [c_char; MAX_SESSION_TOKEN_LEN],
-    pub ownership: OwnershipType,
+    pub session_token: SessionToken,
+    // read only here, changes go through transition_ownership like for UserStruct
+    ownership: OwnershipType,
+    pub ref_count: c_int,
+    pub allocator: AllocatorType,
+    pub role: Role,
 }
 
 #[repr(C)]
@@ -30,38 +40,84 @@ pub struct UserDatabaseT {
     pub count: c_int,
     pub capacity: c_int,
 }
-/*
+
+// What a C database operation did with the user it was given, see database_enhanced.c for the
+// meaning of each field. Only read here, the rest of the crate gets a Result
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
 pub struct DatabaseOperationResult {
-    success: i8,
-    requires_dealloc: i8,
-    ownership_transfer: i8,
-    updated_ptr: *mut UserStructT,
+    success: c_int,
+    requires_deallocation: c_int,
+    ownership_transfer: c_int,
+    updated_pointer: *mut UserStructT,
+    count: c_int,
+}
+
+// MergeCallback in database_enhanced.c
+type MergeCallback =
+    unsafe extern "C" fn(context: *mut c_void, merged: *const UserStructT, survivor: *const UserStructT);
+
+// a duplicate C dropped in merge_duplicate_handles and the record it was merged into
+#[derive(Debug, Clone, PartialEq)]
+pub struct MergedDuplicate {
+    pub username: String,
+    pub merged_user_id: i32,
+    pub survivor_user_id: i32,
+}
+
+// context is the Vec<MergedDuplicate> merge_duplicate_users passes in, both users are valid for the call
+unsafe extern "C" fn record_merge(
+    context: *mut c_void,
+    merged: *const UserStructT,
+    survivor: *const UserStructT,
+) {
+    let merges = &mut *(context as *mut Vec<MergedDuplicate>);
+    merges.push(MergedDuplicate {
+        username: (*merged).username.as_str().to_string(),
+        merged_user_id: (*merged).user_id,
+        survivor_user_id: (*survivor).user_id,
+    });
 }
-*/
 
 extern "C" {
-    fn init_database(dc: *const i32) -> *mut UserDatabaseT;
-    fn create_user(
+    fn init_database(dc: *const i32, next_user_id: *mut i32) -> *mut UserDatabaseT;
+    fn create_user_with_hash(
         username: *const c_char,
         email: *const c_char,
         user_id: c_int,
-        password: *const c_char,
+        password_hash: *const PasswordHash,
     ) -> *mut UserStructT;
-    fn add_user(db: *mut UserDatabaseT, user: *mut UserStructT);
+    fn add_user(db: *mut UserDatabaseT, user: *mut UserStructT) -> DatabaseOperationResult;
+    fn get_last_user_id() -> c_int;
+    fn forget_user_reference(user: *mut UserStructT);
+    fn clone_user_record(src: *const UserStructT) -> *mut UserStructT;
     // fn find_user_by_id(db: *mut UserDatabaseT, user_id: c_int) -> *mut UserStructT;
 
     // Session management
     pub fn create_user_session(user: *const UserStructT) -> *mut c_char;
     fn validate_user_session(token: *const c_char) -> c_int;
+    fn free_session_token(token: *mut c_char);
+    fn issue_user_handle(user: *mut UserStructT) -> UserHandle;
+    fn resolve_user_handle(handle: UserHandle) -> *mut UserStructT;
 
     // Memory management and optimization
     fn get_user_reference_for_debugging(db: *mut UserDatabaseT) -> *mut *mut UserStructT;
 
     // Additional C functions present in database_enhanced.c
     fn print_database(db: *mut UserDatabaseT);
-    fn update_database_daily(db: *mut UserDatabaseT);
-    fn user_login(db: *mut UserDatabaseT, user_name: *const c_char) -> *const c_char;
-    fn get_password(db: *mut UserDatabaseT, user_name: *const c_char) -> *const c_char;
+    fn update_database_daily(db: *mut UserDatabaseT, rust_db: *mut c_void);
+    fn merge_duplicate_handles(
+        db: *mut UserDatabaseT,
+        on_merge: Option<MergeCallback>,
+        context: *mut c_void,
+    ) -> DatabaseOperationResult;
+    fn memory_pressure_cleanup(db: *mut UserDatabaseT) -> DatabaseOperationResult;
+    fn user_login(db: *mut UserDatabaseT, user_name: *const c_char) -> *mut c_char;
+    fn verify_password(
+        db: *mut UserDatabaseT,
+        user_name: *const c_char,
+        candidate: *const c_char,
+    ) -> c_int;
     fn get_non_null_ref_count(db: *mut UserDatabaseT) -> c_int;
     fn find_user_by_username(db: *mut UserDatabaseT, user_name: *const c_char) -> *mut UserStructT;
     fn deactivate_users(db: *mut UserDatabaseT);
@@ -69,6 +125,143 @@ extern "C" {
     fn update_day_counter(dc: *const i32);
 }
 
+// Hashing is done on the Rust side for both backends, C calls these when creating/checking users
+// (passwords outside the shared length limit are rejected here too, never truncated)
+#[no_mangle]
+pub extern "C" fn db_hash_password(password: *const c_char, out: *mut PasswordHash) -> c_int {
+    if password.is_null() || out.is_null() {
+        return -1;
+    }
+    unsafe {
+        let password = Password::new(CStr::from_ptr(password).to_string_lossy().into_owned());
+        if let Err(e) = password_policy::check_length(password.expose()) {
+            log::warn!("REJECTED PASSWORD FROM C: {}", e);
+            return -1;
+        }
+        match hash_password(&password) {
+            Ok(hash) => *out = hash,
+            Err(e) => {
+                log::error!("COULD NOT HASH PASSWORD FROM C: {}", e);
+                return -1;
+            }
+        }
+    }
+    0
+}
+
+#[no_mangle]
+pub extern "C" fn db_verify_password(record: *const PasswordHash, candidate: *const c_char) -> c_int {
+    if record.is_null() || candidate.is_null() {
+        return 0;
+    }
+    unsafe {
+        let candidate = Password::new(CStr::from_ptr(candidate).to_string_lossy().into_owned());
+        if password_policy::check_length(candidate.expose()).is_err() {
+            return 0;
+        }
+        verify_password_hash(&*record, &candidate) as c_int
+    }
+}
+
+// C's update_database_daily asks the Rust database's retention policy about every user, so both
+// backends remove the same users. rust_db is the *mut UserDatabase passed in by increment_day
+#[no_mangle]
+pub extern "C" fn db_retention_decide(
+    rust_db: *mut c_void,
+    role: c_int,
+    inactivity_count: c_int,
+) -> c_int {
+    // a role C does not know is kept rather than guessed at
+    let role = match Role::from_i32(role) {
+        Some(role) => role,
+        None => return RetentionDecision::Keep as c_int,
+    };
+    let decision = if rust_db.is_null() {
+        FixedThreshold::default().decide(role, inactivity_count)
+    } else {
+        unsafe {
+            (*(rust_db as *mut UserDatabase))
+                .retention_policy
+                .decide(role, inactivity_count)
+        }
+    };
+    decision as c_int
+}
+
+// Called by C right before it frees a user it removed, the record is copied into the Rust archive
+// (users C does not own are still held by Rust, which archives them itself)
+#[no_mangle]
+pub extern "C" fn db_archive_user(rust_db: *mut c_void, user: *const UserStructT) {
+    if rust_db.is_null() || user.is_null() {
+        return;
+    }
+    unsafe {
+        if (*user).ownership != OwnershipType::C_OWNED {
+            return;
+        }
+        // same repr(C) layout, like read_user - the archive gets a rust allocated copy of its own
+        let record = UserRecord::new((*(user as *const UserStruct)).clone());
+        let days = record.inactivity_count;
+        archive_user(
+            &mut *(rust_db as *mut UserDatabase),
+            record,
+            RemovalReason::Inactivity { days },
+        );
+    }
+}
+
+// C's audit events go to the same sink as the Rust ones, detail is "key=value key=value"
+// (values that are whole numbers are written as numbers)
+#[no_mangle]
+pub extern "C" fn db_audit_event(
+    day: c_int,
+    event: *const c_char,
+    user: *const UserStructT,
+    detail: *const c_char,
+) {
+    if event.is_null() {
+        return;
+    }
+    unsafe {
+        let mut entry = AuditEvent::new(day, &CStr::from_ptr(event).to_string_lossy());
+        if !user.is_null() {
+            entry = entry.user(Some((*user).user_id), (*user).username.as_str());
+        }
+        if !detail.is_null() {
+            for pair in CStr::from_ptr(detail).to_string_lossy().split_whitespace() {
+                let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+                entry = match value.parse::<i32>() {
+                    Ok(n) => entry.field(key, n),
+                    Err(_) => entry.field(key, value),
+                };
+            }
+        }
+        audit::record(entry);
+    }
+}
+
+// db_log in database_enhanced.c checks this first, so a filtered out line is never formatted
+#[no_mangle]
+pub extern "C" fn db_log_enabled(level: c_int) -> c_int {
+    match logging::level_from_c(level) {
+        Some(level) => log::log_enabled!(target: "c", level) as c_int,
+        None => 0,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn db_log_message(level: c_int, message: *const c_char) {
+    let level = match logging::level_from_c(level) {
+        Some(level) => level,
+        None => return,
+    };
+    if message.is_null() {
+        return;
+    }
+    let message = unsafe { CStr::from_ptr(message).to_string_lossy() };
+    log::log!(target: "c", level, "{}", message.trim_end());
+}
+
 /*
 //function to transform into a C array while null terminating to avoid buffer overflow
 fn to_c_array<const N: usize>(src: &[u8; N]) -> [c_char; N] {
@@ -101,98 +294,163 @@ fn to_rust_array<const N: usize>(src: &[c_char; N]) -> [u8; N] {
 }
 */
 
+// A C user as Rust may keep it between calls: a slot in the C handle table plus the generation
+// it was issued for. C bumps the generation whenever it frees or drops the user, so an old handle
+// resolves to None instead of a dangling pointer (see resolve_handle)
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UserHandle {
+    slot: u32,
+    generation: u32,
+}
+
 pub struct UserReference {
     pub username: String,
-    pub ptr: *mut UserStructT,
+    pub handle: UserHandle,
 }
 
 impl UserReference {
-    pub fn new(username: String, ptr: *mut UserStructT) -> Self {
-        UserReference { username, ptr }
+    pub fn new(username: String, handle: UserHandle) -> Self {
+        UserReference { username, handle }
     }
 }
 
+// C mallocs every token it hands out - copy it into a SessionToken, then let C wipe and free it
+unsafe fn take_c_token(token_ptr: *mut c_char) -> SessionToken {
+    let mut raw = [0u8; MAX_SESSION_TOKEN_LEN];
+    let bytes = CStr::from_ptr(token_ptr).to_bytes();
+    let len = bytes.len().min(MAX_SESSION_TOKEN_LEN - 1);
+    raw[..len].copy_from_slice(&bytes[..len]);
+    free_session_token(token_ptr);
+    let token = SessionToken::from_raw(raw);
+    raw.zeroize();
+    token
+}
+
+// the C database, its session manager and handle table are globals, tests that create a
+// DatabaseExtensions hold this for as long as they use it
+#[cfg(test)]
+pub static C_BACKEND_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
 pub struct DatabaseExtensions {
     db: *mut UserDatabaseT,
+    // the same day counter C reads, for the days the merge and compaction passes run on
+    day_counter: *const i32,
 }
 
 impl DatabaseExtensions {
-    pub fn new(dc: *const i32) -> Self {
-        let db = unsafe { init_database(dc) };
+    pub fn new(dc: *const i32, next_user_id: *mut i32) -> Self {
+        let db = unsafe { init_database(dc, next_user_id) };
         unsafe {
             init_session_manager();
         }
-        DatabaseExtensions { db }
+        DatabaseExtensions {
+            db,
+            day_counter: dc,
+        }
     }
-    pub fn get_user_password(&self, user: *mut UserStructT) -> String {
-        unsafe {
-            let password_ptr = get_password(self.db, (*user).username.as_ptr());
-            CStr::from_ptr(password_ptr).to_string_lossy().to_string()
+    // C either takes over the reference to user or hands it back with requires_deallocation, then
+    // it is released here (which frees the user unless someone else still holds it)
+    unsafe fn add_to_c_db(&self, user: *mut UserStructT) -> Result<(), String> {
+        let result = add_user(self.db, user);
+        if result.success != 0 && result.ownership_transfer != 0 {
+            return Ok(());
+        }
+        if result.requires_deallocation != 0 {
+            user_release(user as *mut UserStruct);
         }
+        Err("C database is full".to_string())
+    }
+    pub fn verify_user_password(&self, user: *mut UserStructT, candidate: &Password) -> bool {
+        let mut c_candidate = match CString::new(candidate.expose()) {
+            Ok(s) => s.into_bytes_with_nul(),
+            Err(_) => return false,
+        };
+        let ok = unsafe {
+            verify_password(self.db, (*user).username.as_ptr(), c_candidate.as_ptr() as *const c_char) != 0
+        };
+        c_candidate.zeroize();
+        ok
     }
     pub fn get_user_in_c_backend(&self, username: &str) -> *mut UserStructT {
-        println!("[RUST] GET_USER_IN_C_BACKEND() - username: {:?}", username);
+        log::debug!("GET_USER_IN_C_BACKEND() - username: {:?}", username);
         let c_username = match CString::new(username) {
             Ok(s) => s,
             Err(_) => return std::ptr::null_mut(),
         };
-        println!(
-            "[RUST] AQCUIRED C_USERNAME: {:?} vs USERNAME: {:?}",
+        log::trace!(
+            "AQCUIRED C_USERNAME: {:?} vs USERNAME: {:?}",
             c_username, username
         );
         unsafe {
-            println!("[RUST] GETTING POINTER TO USER!");
-            println!(
-                "[RUST] PASSING IN C_USERNAME AS A POINTER: {:?}",
+            log::trace!("GETTING POINTER TO USER!");
+            log::trace!(
+                "PASSING IN C_USERNAME AS A POINTER: {:?}",
                 c_username.as_ptr()
             );
             let user_ptr = find_user_by_username(self.db, c_username.as_ptr());
-            println!("[RUST] USER_PTR WAS ASSIGNED!");
+            log::trace!("USER_PTR WAS ASSIGNED!");
             if user_ptr.is_null() {
-                println!("[RUST] USER POINTER IS NULL!!");
+                log::debug!("USER POINTER IS NULL!!");
                 std::ptr::null_mut()
             } else {
-                println!("[RUST] GOT USER POINTER: {:?}", user_ptr);
+                log::trace!("GOT USER POINTER: {:?}", user_ptr);
                 user_ptr
             }
         }
     }
     pub fn get_last_user_id(&self) -> i32 {
-        unsafe { (*self.db).count - 1 }
+        unsafe { get_last_user_id() }
     }
     pub fn sync_user_to_c_backend(
         &self,
         username: &str,
         email: &str,
         user_id: i32,
-        password: &str,
+        password_hash: &PasswordHash,
+        role: Role,
     ) -> Result<(), String> {
-        let c_username = CString::new(username).map_err(|_| "Invalid username")?;
-        let c_email = CString::new(email).map_err(|_| "Invalid email")?;
-        let c_password = CString::new(password).map_err(|_| "Invalid password")?;
+        if username.contains('\0') {
+            return Err("Invalid username".to_string());
+        }
+        if email.contains('\0') {
+            return Err("Invalid email".to_string());
+        }
+        // the plaintext was checked against the password policy before it was hashed,
+        // an empty record here would be a user nobody can ever log in as
+        if !password_hash.is_valid() {
+            return Err("Invalid password hash".to_string());
+        }
+        // the caller checks the rust database, names only differing in case clash here too
+        if !self.get_user_in_c_backend(username).is_null() {
+            return Err(DbError::DuplicateUsername.to_string());
+        }
+        // cut here on a character boundary (like create_user on the rust side) rather than by bytes in C
+        let c_username = FixedStr::<MAX_NAME_LEN>::truncated(username);
+        let c_email = FixedStr::<MAX_EMAIL_LEN>::truncated(email);
 
-        println!("CREATING USER TO SYNC WITH C BACKEND: {:?}", c_username);
+        log::debug!("CREATING USER TO SYNC WITH C BACKEND: {:?}", c_username);
         unsafe {
-            let user = create_user(
+            let user = create_user_with_hash(
                 c_username.as_ptr(),
                 c_email.as_ptr(),
                 user_id,
-                c_password.as_ptr(),
+                password_hash,
             );
-            (*user).ownership = OwnershipType::C_OWNED;
-            println!("[RUST] SET OWNERSHIP OF C USER: {:?}", (*user).ownership);
             if user.is_null() {
                 return Err("Failed to create user".to_string());
             }
-            println!("[RUST] ADDING USER TO DB");
-            add_user(self.db, user);
+            // created C_OWNED by C
+            log::trace!("OWNERSHIP OF C USER: {:?}", (*user).ownership);
+            (*user).role = role;
+            log::trace!("ADDING USER TO DB");
+            self.add_to_c_db(user)
         }
-        Ok(())
     }
     pub fn sync_user_from_rust_db(&self, user: *mut UserStructT) {
         unsafe {
-            println!(
-                "[RUST] SYNC_USER_FROM_RUST_DB() USER: {:?} \nOWNERSHIP: {:?}",
+            log::trace!(
+                "SYNC_USER_FROM_RUST_DB() USER: {:?} OWNERSHIP: {:?}",
                 user,
                 (*user).ownership
             );
@@ -200,14 +458,25 @@ impl DatabaseExtensions {
             if (*user).ownership == OwnershipType::SHARED_C_PRIMARY
                 || (*user).ownership == OwnershipType::SHARED_RUST_PRIMARY
             {
-                println!("[RUST] USER ALREADY SHARED - SKIP");
+                log::debug!("USER ALREADY SHARED - SKIP");
                 return;
             }
 
-            (*user).ownership = OwnershipType::SHARED_C_PRIMARY;
-            println!("[RUST] AFTER SYNC OWNERSHIP: {:?}", (*user).ownership);
-            add_user(self.db, user);
-            println!("[RUST] USER ADDED TO C DATABASE");
+            let rust_user = &mut *(user as *mut UserStruct);
+            if let Err(e) = transition_ownership(rust_user, OwnershipType::SHARED_C_PRIMARY) {
+                log::warn!("USER {:?} NOT SHARED WITH C: {}", (*user).username, e);
+                return;
+            }
+            // the C database holds a reference of its own, rust_db keeps the one it has
+            user_retain(user as *mut UserStruct);
+            if let Err(e) = self.add_to_c_db(user) {
+                log::warn!("USER {:?} NOT SHARED WITH C: {}", (*user).username, e);
+                // C never took it, rust_db is the only holder again
+                let _ = transition_ownership(rust_user, OwnershipType::RUST_OWNED);
+                return;
+            }
+            log::trace!("AFTER SYNC OWNERSHIP: {:?}", (*user).ownership);
+            log::debug!("USER ADDED TO C DATABASE");
         }
     }
 
@@ -215,10 +484,10 @@ impl DatabaseExtensions {
         user as *const UserStruct as *const UserStructT
     }
 
-    pub fn create_session(&self, user: &UserStruct) -> Result<String, String> {
+    pub fn create_session(&self, user: &UserStruct) -> Result<SessionToken, String> {
         /*
         let c_user = UserStructT {
-            password: to_c_array::<MAX_PASSWORD_LENGTH>(&user.password),
+            password_hash: user.password_hash.clone(),
             username: to_c_array::<MAX_NAME_LEN>(&user.username),
             email: to_c_array::<MAX_EMAIL_LEN>(&user.email),
             session_token: to_c_array::<MAX_SESSION_TOKEN_LEN>(&user.session_token),
@@ -235,17 +504,14 @@ impl DatabaseExtensions {
             if token_ptr.is_null() {
                 return Err("Failed to create session".to_string());
             }
-
-            let token = CStr::from_ptr(token_ptr).to_string_lossy().to_string();
-            Ok(token)
+            Ok(take_c_token(token_ptr))
         }
     }
 
-    pub fn validate_session(&self, token: &str) -> Result<i32, String> {
-        let c_token = CString::new(token).map_err(|_| "Invalid token")?;
-
+    pub fn validate_session(&self, token: &SessionToken) -> Result<i32, String> {
         unsafe {
-            let user_id = validate_user_session(c_token.as_ptr());
+            // the field is always NUL terminated, so C can read it in place
+            let user_id = validate_user_session(token.as_ptr());
             if user_id == 0 {
                 Err("Invalid session".to_string())
             } else {
@@ -254,44 +520,101 @@ impl DatabaseExtensions {
         }
     }
 
-    pub fn login_user(&self, user_name: &str) -> Result<String, String> {
+    pub fn login_user(&self, user_name: &str) -> Result<SessionToken, String> {
         unsafe {
             let c_user_name = CString::new(user_name).map_err(|_| "Invalid username")?;
             let token_ptr = user_login(self.db, c_user_name.as_ptr());
             if token_ptr.is_null() {
                 return Err("Failed to create session".to_string());
             }
-            Ok(CStr::from_ptr(token_ptr).to_string_lossy().to_string())
+            Ok(take_c_token(token_ptr))
         }
     }
 
-    pub fn get_all_user_references(&self) -> Vec<Box<UserStruct>> {
-        println!("[RUST] GETTING ALL USER REFS");
+    pub fn get_all_user_references(&self) -> Vec<UserRecord> {
+        log::trace!("GETTING ALL USER REFS");
         let refs = unsafe { get_user_reference_for_debugging(self.db) };
         let ref_count = unsafe { get_non_null_ref_count(self.db) };
-        println!("[RUST] REF COUNT: {:?}", ref_count);
+        log::trace!("REF COUNT: {:?}", ref_count);
         let mut user_refs = Vec::new();
         let refs_slice = unsafe { std::slice::from_raw_parts(refs, ref_count as usize) };
         for &user_ptr in refs_slice {
             if !user_ptr.is_null() {
                 unsafe {
                     let c_user = &*user_ptr;
-                    if c_user.ownership == OwnershipType::SHARED_C_PRIMARY {
-                        let mut rust_user = Box::from_raw(user_ptr as *mut UserStruct);
-                        rust_user.ownership = OwnershipType::SHARED_RUST_PRIMARY;
-                        user_refs.push(rust_user);
+                    // SHARED_C_PRIMARY users are the rust users sync_user_from_rust_db just handed to C,
+                    // rust_db already holds them - only C allocated users that havent been shared yet
+                    // are handed over, each with a reference of its own (C keeps the one it has)
+                    if c_user.ownership == OwnershipType::C_OWNED {
+                        let mut rust_user = UserRecord::retain(user_ptr as *mut UserStruct);
+                        // refused: rust_user is dropped here and C stays the only holder
+                        if transition_ownership(&mut rust_user, OwnershipType::SHARED_RUST_PRIMARY)
+                            .is_ok()
+                        {
+                            user_refs.push(rust_user);
+                        }
                     }
                 };
             }
         }
         return user_refs;
     }
-    pub fn increment_day(&self, rust_db: &UserDatabase) {
+    // returns the duplicates merge_duplicate_users dropped, empty on other days
+    pub fn increment_day(&self, rust_db: &mut UserDatabase) -> Vec<MergedDuplicate> {
+        // C only uses the pointer during the call, to reach the retention policy and the archive
+        let rust_db_ptr = rust_db as *mut UserDatabase as *mut c_void;
         unsafe {
-            println!("[RUST] DB WRAPPER INCREMENT!\n");
-            update_database_daily(self.db);
+            log::trace!("DB WRAPPER INCREMENT!");
+            update_database_daily(self.db, rust_db_ptr);
             //self.deactivate_idle_users(rust_db);
         }
+        let day = unsafe { *self.day_counter };
+        let mut merged = Vec::new();
+        if day % 4 == 0 {
+            merged = self.merge_duplicate_users();
+        }
+        if day % 8 == 0 {
+            match self.compact() {
+                Ok(moved) => log::debug!("C COMPACTION MOVED {} USERS", moved),
+                Err(e) => log::warn!("{}", e),
+            }
+        }
+        merged
+    }
+    // C drops every duplicate alias of a user and keeps the latest one. the dropped aliases are
+    // released (rust users stay alive in rust_db) and their handles invalidated. C reports every
+    // dropped alias with the user it was merged into, so the caller can drop what it cached for them
+    pub fn merge_duplicate_users(&self) -> Vec<MergedDuplicate> {
+        log::debug!("CALLING MERGE DUPLICATE HANDLES()");
+        let mut merged: Vec<MergedDuplicate> = Vec::new();
+        let context = &mut merged as *mut Vec<MergedDuplicate> as *mut c_void;
+        let result = unsafe { merge_duplicate_handles(self.db, Some(record_merge), context) };
+        if result.count as usize != merged.len() {
+            log::warn!(
+                "C REPORTED {} MERGED DUPLICATES BUT CALLED BACK FOR {}",
+                result.count,
+                merged.len()
+            );
+        }
+        for duplicate in &merged {
+            log::info!(
+                "C MERGED USER {} INTO USER {} ({:?})",
+                duplicate.merged_user_id,
+                duplicate.survivor_user_id,
+                duplicate.username
+            );
+        }
+        merged
+    }
+    // moves the C users to the front of the array, no user changes hands and handles stay valid.
+    // returns how many users were moved
+    pub fn compact(&self) -> Result<usize, String> {
+        log::debug!("CALLING MEMORY PRESSURE CLEANUP()");
+        let result = unsafe { memory_pressure_cleanup(self.db) };
+        if result.success == 0 {
+            return Err("C compaction failed".to_string());
+        }
+        Ok(result.count as usize)
     }
     pub fn deactivate_idle_users(&self, db: &UserDatabase) {
         unsafe {
@@ -299,25 +622,159 @@ impl DatabaseExtensions {
             deactivate_users(db_ptr);
         }
     }
-    pub fn create_session_for_c_ptr(&self, user: *const UserStructT) -> Result<String, String> {
+    pub fn create_session_for_c_ptr(&self, user: *const UserStructT) -> Result<SessionToken, String> {
         unsafe {
             let token_ptr = create_user_session(user);
             if token_ptr.is_null() {
                 return Err("Failed to create session".to_string());
             }
-            let token = CStr::from_ptr(token_ptr).to_string_lossy().to_string();
-            Ok(token)
+            Ok(take_c_token(token_ptr))
+        }
+    }
+    // None when the C handle table is full, the user then just isnt cached
+    pub fn issue_handle(&self, user: *mut UserStructT) -> Option<UserHandle> {
+        if user.is_null() {
+            return None;
+        }
+        let handle = unsafe { issue_user_handle(user) };
+        if handle.slot as usize >= MAX_USERS {
+            return None;
+        }
+        Some(handle)
+    }
+    // the pointer is only good until the next call into C, never store it - store the handle
+    pub fn resolve_handle(&self, handle: UserHandle) -> Option<*mut UserStructT> {
+        let user = unsafe { resolve_user_handle(handle) };
+        if user.is_null() {
+            None
+        } else {
+            Some(user)
+        }
+    }
+    // every non-null pointer the C database holds (includes rust users shared during a join)
+    pub fn c_user_pointers(&self) -> Vec<*mut UserStructT> {
+        unsafe {
+            let db = &*self.db;
+            let count = db.count.max(0) as usize;
+            db.users[..count]
+                .iter()
+                .copied()
+                .filter(|ptr| !ptr.is_null())
+                .collect()
+        }
+    }
+    // copy of a user from the C database, the structs share the same repr(C) layout
+    pub fn read_user(&self, user: *mut UserStructT) -> UserStruct {
+        unsafe { (*(user as *const UserStruct)).clone() }
+    }
+    // snapshot restore: C allocates a copy of a user it owned when the snapshot was taken
+    pub fn restore_c_user(&self, user: &UserStruct, add_to_c_db: bool) -> Result<*mut UserStructT, String> {
+        unsafe {
+            let ptr = clone_user_record(DatabaseExtensions::cast_user_struct(user));
+            if ptr.is_null() {
+                return Err("Failed to allocate user".to_string());
+            }
+            if add_to_c_db {
+                // the copy is released (freed) if C could not take it
+                self.add_to_c_db(ptr)?;
+            }
+            Ok(ptr)
+        }
+    }
+    // snapshot restore: puts an existing (rust allocated) user into the C database as an alias
+    pub fn add_user_reference(&self, user: *mut UserStructT) -> Result<(), String> {
+        unsafe {
+            user_retain(user as *mut UserStruct);
+            self.add_to_c_db(user)
         }
     }
+    // snapshot restore: a C allocated user only rust holds, takes over the reference restore_c_user made
+    pub fn box_c_user(&self, user: *mut UserStructT) -> UserRecord {
+        unsafe { UserRecord::from_raw(user as *mut UserStruct) }
+    }
+    // the rust side alias of a C allocated user the C database keeps holding, like
+    // get_all_user_references does
+    pub fn share_c_user(&self, user: *mut UserStructT) -> UserRecord {
+        unsafe { UserRecord::retain(user as *mut UserStruct) }
+    }
     pub fn print_database_full(&self) {
-        println!("[C] PRINTING DATABASE!");
+        log::trace!("PRINTING DATABASE!");
         unsafe {
             print_database(self.db);
         }
     }
 }
 
-pub fn initialize_enhanced_database(dc: &i32) -> DatabaseExtensions {
+// Called from the rust database's on_user_removed hook - a rust user that was shared with C during a
+// join has to be dropped from the C database when rust removes it, otherwise C keeps a stale alias
+// (C releases the references it held, the archive keeps the record alive)
+pub fn forget_c_reference(user: &UserStruct) {
+    unsafe {
+        forget_user_reference(user as *const UserStruct as *mut UserStructT);
+    }
+}
+
+pub fn initialize_enhanced_database(dc: &i32, id_allocator: &mut IdAllocator) -> DatabaseExtensions {
     let dc_ptr = dc as *const i32;
-    DatabaseExtensions::new(dc_ptr)
+    let next_id_ptr = &mut id_allocator.next_user_id as *mut i32;
+    DatabaseExtensions::new(dc_ptr, next_id_ptr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_fix_full::init_database;
+
+    fn hash() -> PasswordHash {
+        PasswordHash {
+            version: 1,
+            iterations: 1000,
+            salt: [1; 16],
+            hash: [2; 32],
+        }
+    }
+
+    #[test]
+    fn merges_report_the_dropped_and_surviving_user() {
+        let _lock = C_BACKEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let day: &'static i32 = Box::leak(Box::new(1));
+        let mut rust_db = init_database();
+        let c_db = initialize_enhanced_database(day, &mut rust_db.id_allocator);
+        // signups cannot create duplicates any more, restoring the same record twice still can
+        for user_id in [7, 8] {
+            let mut user = UserStruct::default();
+            user.username = FixedStr::truncated("dave");
+            user.email = FixedStr::truncated("dave@nus.edu.sg");
+            user.user_id = user_id;
+            user.password_hash = hash();
+            c_db.restore_c_user(&user.with_ownership(OwnershipType::C_OWNED), true)
+                .unwrap();
+        }
+        assert_eq!(
+            c_db.merge_duplicate_users(),
+            [MergedDuplicate {
+                username: "dave".to_string(),
+                merged_user_id: 7,
+                survivor_user_id: 8,
+            }]
+        );
+        assert_eq!(c_db.c_user_pointers().len(), 1);
+        assert!(c_db.merge_duplicate_users().is_empty());
+    }
+
+    #[test]
+    fn c_usernames_ignore_ascii_case_like_rust() {
+        let _lock = C_BACKEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let day: &'static i32 = Box::leak(Box::new(1));
+        let mut rust_db = init_database();
+        let c_db = initialize_enhanced_database(day, &mut rust_db.id_allocator);
+        let hash = hash();
+        c_db.sync_user_to_c_backend("Carol", "carol@nus.edu.sg", 0, &hash, Role::Student)
+            .unwrap();
+        assert!(!c_db.get_user_in_c_backend("CAROL").is_null());
+        assert_eq!(
+            c_db.sync_user_to_c_backend("carol", "carol2@nus.edu.sg", 0, &hash, Role::Student),
+            Err(DbError::DuplicateUsername.to_string())
+        );
+    }
 }
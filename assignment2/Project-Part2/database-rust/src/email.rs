@@ -0,0 +1,251 @@
+/* ------email.rs------
+ *
+ * email validation for signups, a practical subset of RFC 5322:
+ *   local part:  dot-atom (letters, digits and !#$%&'*+/=?^_`{|}~- separated by single dots),
+ *                no quoted strings or comments
+ *   domain:      at least two dot separated labels of letters, digits and '-' (not at either end
+ *                of a label), the last label is alphabetic - no IP literals
+ *   the whole address has to fit in the email field of UserStruct, it is never truncated
+ *
+ * normalization trims surrounding whitespace and lowercases the domain, the local part is kept as
+ * typed since RFC 5322 leaves its case to the receiving server
+ */
+
+// same size as the email field in UserStruct / UserStruct_t, one byte is the NUL terminator
+pub const MAX_EMAIL_LEN: usize = 50;
+pub const MAX_LOCAL_PART_LEN: usize = 64;
+pub const MAX_DOMAIN_LABEL_LEN: usize = 63;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EmailError {
+    Empty,
+    TooLong { len: usize, max: usize },
+    MissingAt,
+    MultipleAt,
+    EmptyLocalPart,
+    LocalPartTooLong { len: usize, max: usize },
+    InvalidLocalChar(char),
+    // leading, trailing or consecutive dots in the local part
+    MisplacedDot,
+    EmptyDomain,
+    // "emoji@.com", "a@b..com"
+    EmptyDomainLabel,
+    DomainLabelTooLong { label: String, max: usize },
+    InvalidDomainChar(char),
+    DomainLabelHyphen { label: String },
+    // a single label like "segfault@0xdeadbeef"
+    DomainNotQualified,
+    InvalidTopLevelDomain { tld: String },
+}
+
+impl std::fmt::Display for EmailError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EmailError::Empty => write!(f, "email is empty"),
+            EmailError::TooLong { len, max } => {
+                write!(f, "email is {} bytes long (max {})", len, max)
+            }
+            EmailError::MissingAt => write!(f, "email has no '@'"),
+            EmailError::MultipleAt => write!(f, "email has more than one '@'"),
+            EmailError::EmptyLocalPart => write!(f, "email has nothing before the '@'"),
+            EmailError::LocalPartTooLong { len, max } => {
+                write!(f, "part before the '@' is {} bytes long (max {})", len, max)
+            }
+            EmailError::InvalidLocalChar(c) => {
+                write!(f, "character {:?} is not allowed before the '@'", c)
+            }
+            EmailError::MisplacedDot => {
+                write!(f, "dots before the '@' cannot be leading, trailing or doubled")
+            }
+            EmailError::EmptyDomain => write!(f, "email has no domain"),
+            EmailError::EmptyDomainLabel => write!(f, "domain has an empty label"),
+            EmailError::DomainLabelTooLong { label, max } => {
+                write!(f, "domain label {:?} is longer than {} bytes", label, max)
+            }
+            EmailError::InvalidDomainChar(c) => {
+                write!(f, "character {:?} is not allowed in the domain", c)
+            }
+            EmailError::DomainLabelHyphen { label } => {
+                write!(f, "domain label {:?} starts or ends with '-'", label)
+            }
+            EmailError::DomainNotQualified => write!(f, "domain needs at least one dot"),
+            EmailError::InvalidTopLevelDomain { tld } => {
+                write!(f, "top level domain {:?} is not alphabetic", tld)
+            }
+        }
+    }
+}
+
+impl std::error::Error for EmailError {}
+
+fn is_atext(c: char) -> bool {
+    c.is_ascii_alphanumeric() || "!#$%&'*+/=?^_`{|}~-".contains(c)
+}
+
+fn check_local_part(local: &str) -> Result<(), EmailError> {
+    if local.is_empty() {
+        return Err(EmailError::EmptyLocalPart);
+    }
+    if local.len() > MAX_LOCAL_PART_LEN {
+        return Err(EmailError::LocalPartTooLong {
+            len: local.len(),
+            max: MAX_LOCAL_PART_LEN,
+        });
+    }
+    if let Some(c) = local.chars().find(|&c| c != '.' && !is_atext(c)) {
+        return Err(EmailError::InvalidLocalChar(c));
+    }
+    if local.starts_with('.') || local.ends_with('.') || local.contains("..") {
+        return Err(EmailError::MisplacedDot);
+    }
+    Ok(())
+}
+
+fn check_domain(domain: &str) -> Result<(), EmailError> {
+    if domain.is_empty() {
+        return Err(EmailError::EmptyDomain);
+    }
+    let labels: Vec<&str> = domain.split('.').collect();
+    for label in &labels {
+        if label.is_empty() {
+            return Err(EmailError::EmptyDomainLabel);
+        }
+        if label.len() > MAX_DOMAIN_LABEL_LEN {
+            return Err(EmailError::DomainLabelTooLong {
+                label: label.to_string(),
+                max: MAX_DOMAIN_LABEL_LEN,
+            });
+        }
+        if let Some(c) = label
+            .chars()
+            .find(|&c| !c.is_ascii_alphanumeric() && c != '-')
+        {
+            return Err(EmailError::InvalidDomainChar(c));
+        }
+        if label.starts_with('-') || label.ends_with('-') {
+            return Err(EmailError::DomainLabelHyphen {
+                label: label.to_string(),
+            });
+        }
+    }
+    if labels.len() < 2 {
+        return Err(EmailError::DomainNotQualified);
+    }
+    let tld = labels[labels.len() - 1];
+    if tld.len() < 2 || !tld.chars().all(|c| c.is_ascii_alphabetic()) {
+        return Err(EmailError::InvalidTopLevelDomain {
+            tld: tld.to_string(),
+        });
+    }
+    Ok(())
+}
+
+/// Validate an email address and return it normalized (trimmed, domain lowercased)
+pub fn normalize_email(email: &str) -> Result<String, EmailError> {
+    let email = email.trim();
+    if email.is_empty() {
+        return Err(EmailError::Empty);
+    }
+    let (local, domain) = match email.split_once('@') {
+        Some(parts) => parts,
+        None => return Err(EmailError::MissingAt),
+    };
+    if domain.contains('@') {
+        return Err(EmailError::MultipleAt);
+    }
+    check_local_part(local)?;
+    let domain = domain.to_ascii_lowercase();
+    check_domain(&domain)?;
+
+    let normalized = format!("{}@{}", local, domain);
+    if normalized.len() > MAX_EMAIL_LEN - 1 {
+        return Err(EmailError::TooLong {
+            len: normalized.len(),
+            max: MAX_EMAIL_LEN - 1,
+        });
+    }
+    Ok(normalized)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_the_domain_is_lowercased() {
+        assert_eq!(
+            normalize_email("  Alice.Smith@Mail.Example.COM\t").as_deref(),
+            Ok("Alice.Smith@mail.example.com")
+        );
+    }
+
+    #[test]
+    fn length_is_checked_after_trimming() {
+        // 49 bytes is the most the email field holds next to its NUL terminator
+        let fits = format!("{}@example.com", "a".repeat(37));
+        assert_eq!(fits.len(), MAX_EMAIL_LEN - 1);
+        assert_eq!(normalize_email(&format!("   {}   ", fits)), Ok(fits.clone()));
+        assert_eq!(
+            normalize_email(&format!("a{}", fits)),
+            Err(EmailError::TooLong { len: 50, max: 49 })
+        );
+    }
+
+    #[test]
+    fn local_part_is_a_dot_atom() {
+        assert!(normalize_email("o'brien+tag@example.com").is_ok());
+        assert_eq!(
+            normalize_email("al..ice@example.com"),
+            Err(EmailError::MisplacedDot)
+        );
+        assert_eq!(
+            normalize_email("alice.@example.com"),
+            Err(EmailError::MisplacedDot)
+        );
+        // quoted local parts are not supported at all
+        assert_eq!(
+            normalize_email("\"al ice\"@example.com"),
+            Err(EmailError::InvalidLocalChar('"'))
+        );
+    }
+
+    #[test]
+    fn a_second_at_is_not_part_of_the_domain() {
+        assert_eq!(
+            normalize_email("oscar@@doubleatsign.com"),
+            Err(EmailError::MultipleAt)
+        );
+        assert_eq!(
+            normalize_email("a@b@example.com"),
+            Err(EmailError::MultipleAt)
+        );
+    }
+
+    #[test]
+    fn domain_needs_qualified_alphabetic_tld() {
+        assert_eq!(
+            normalize_email("segfault@0xdeadbeef"),
+            Err(EmailError::DomainNotQualified)
+        );
+        assert_eq!(
+            normalize_email("emoji@.com"),
+            Err(EmailError::EmptyDomainLabel)
+        );
+        assert_eq!(
+            normalize_email("root@[127.0.0.1]"),
+            Err(EmailError::InvalidDomainChar('['))
+        );
+        assert_eq!(
+            normalize_email("root@127.0.0.1"),
+            Err(EmailError::InvalidTopLevelDomain {
+                tld: "1".to_string()
+            })
+        );
+        assert_eq!(
+            normalize_email("alice@mail-.example.com"),
+            Err(EmailError::DomainLabelHyphen {
+                label: "mail-".to_string()
+            })
+        );
+    }
+}
@@ -0,0 +1,213 @@
+/* ------export.rs------
+ *
+ * machine-readable dumps of both databases, for EnhancedStudentDatabase::export_json / export_csv
+ *
+ * there is one row per user per backend: a user both databases point at (shared during a join)
+ * gets a "rust" row and a "c" row, so one backend can be compared with the other by filtering on
+ * the backend column. rows are sorted by user_id, then username, then backend, so the same
+ * databases always give byte-identical output and two runs can be diffed directly
+ *
+ * columns, in this order in the CSV header:
+ *   user_id, username, email, inactivity_count, is_active, has_session, password_hash_version,
+ *   ownership, backend
+ *
+ * the session token and password hash are never written, only whether there is one
+ *
+ * JSON is an array with one object per line, CSV follows RFC 4180 (fields quoted when needed)
+ */
+
+use crate::audit::push_json_str;
+use crate::query::{Backend, UserView};
+
+pub const CSV_HEADER: &str = "user_id,username,email,inactivity_count,is_active,has_session,password_hash_version,ownership,backend";
+
+struct Row<'v, 'a> {
+    user: &'v UserView<'a>,
+    backend: &'static str,
+}
+
+fn backend_name(backend: Backend) -> &'static str {
+    match backend {
+        Backend::Rust => "rust",
+        Backend::C => "c",
+    }
+}
+
+fn rows<'v, 'a>(users: &'v [UserView<'a>]) -> Vec<Row<'v, 'a>> {
+    let mut rows: Vec<Row> = users
+        .iter()
+        .flat_map(|user| {
+            [Backend::Rust, Backend::C]
+                .into_iter()
+                .filter(move |backend| user.in_backend(*backend))
+                .map(move |backend| Row {
+                    user,
+                    backend: backend_name(backend),
+                })
+        })
+        .collect();
+    rows.sort_by(|a, b| {
+        (a.user.user_id(), a.user.username(), a.backend).cmp(&(
+            b.user.user_id(),
+            b.user.username(),
+            b.backend,
+        ))
+    });
+    rows
+}
+
+pub fn to_json(users: &[UserView]) -> String {
+    let rows = rows(users);
+    let mut out = String::from("[\n");
+    for (i, row) in rows.iter().enumerate() {
+        let user = row.user;
+        out.push_str(&format!("{{\"user_id\":{},\"username\":", user.user_id()));
+        push_json_str(&mut out, user.username());
+        out.push_str(",\"email\":");
+        push_json_str(&mut out, user.email());
+        out.push_str(&format!(
+            ",\"inactivity_count\":{},\"is_active\":{},\"has_session\":{},\"password_hash_version\":{},\"ownership\":\"{:?}\",\"backend\":\"{}\"}}",
+            user.inactivity_count(),
+            user.is_active(),
+            user.has_session(),
+            user.password_hash_version(),
+            user.ownership(),
+            row.backend
+        ));
+        if i + 1 < rows.len() {
+            out.push(',');
+        }
+        out.push('\n');
+    }
+    out.push_str("]\n");
+    out
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) || value.trim() != value {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+pub fn to_csv(users: &[UserView]) -> String {
+    let mut out = String::from(CSV_HEADER);
+    out.push('\n');
+    for row in rows(users) {
+        let user = row.user;
+        out.push_str(&format!(
+            "{},{},{},{},{},{},{},{:?},{}\n",
+            user.user_id(),
+            csv_field(user.username()),
+            csv_field(user.email()),
+            user.inactivity_count(),
+            user.is_active(),
+            user.has_session(),
+            user.password_hash_version(),
+            user.ownership(),
+            row.backend
+        ));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_fix_full::{FixedStr, UserStruct};
+
+    fn view(username: &str, user_id: i32, in_rust: bool, in_c: bool) -> UserView<'static> {
+        let mut user = UserStruct::default();
+        user.username = FixedStr::truncated(username);
+        user.email = FixedStr::truncated(&format!("{}@example.com", user_id));
+        user.user_id = user_id;
+        UserView::detached(user, in_rust, in_c)
+    }
+
+    #[test]
+    fn csv_quoting() {
+        for (value, expected) in [
+            ("alice", "alice"),
+            ("a,b", "\"a,b\""),
+            ("say \"hi\"", "\"say \"\"hi\"\"\""),
+            ("two\nlines", "\"two\nlines\""),
+            ("cr\r", "\"cr\r\""),
+            (" padded", "\" padded\""),
+            ("padded ", "\"padded \""),
+            ("", ""),
+        ] {
+            assert_eq!(csv_field(value), expected, "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn csv_rows() {
+        let users = [view("o,brien", 1, true, false)];
+        assert_eq!(
+            to_csv(&users),
+            format!(
+                "{}\n1,\"o,brien\",1@example.com,0,false,false,0,RUST_OWNED,rust\n",
+                CSV_HEADER
+            )
+        );
+    }
+
+    #[test]
+    fn sorted_the_same_whatever_the_input_order() {
+        let users = vec![
+            view("carol", 3, false, true),
+            view("bob", 2, true, true),
+            view("alice", 1, true, false),
+            // same id as alice, sorted by username after it
+            view("aaron", 1, false, true),
+        ];
+        let mut reversed = users.clone();
+        reversed.reverse();
+        let mut rotated = users.clone();
+        rotated.rotate_left(1);
+
+        let csv = to_csv(&users);
+        let json = to_json(&users);
+        for other in [&reversed, &rotated] {
+            assert_eq!(to_csv(other), csv);
+            assert_eq!(to_json(other), json);
+        }
+
+        // one row per backend, a shared user's "c" row sorts before its "rust" row
+        let rows: Vec<(&str, &str)> = csv
+            .lines()
+            .skip(1)
+            .map(|line| {
+                let fields: Vec<&str> = line.split(',').collect();
+                (fields[1], fields[fields.len() - 1])
+            })
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("aaron", "c"),
+                ("alice", "rust"),
+                ("bob", "c"),
+                ("bob", "rust"),
+                ("carol", "c"),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_escapes_strings() {
+        let json = to_json(&[view("quote\"back\\slash", 1, true, false)]);
+        assert!(
+            json.contains(r#""username":"quote\"back\\slash""#),
+            "{}",
+            json
+        );
+        assert!(
+            json.starts_with("[\n{") && json.ends_with("}\n]\n"),
+            "{}",
+            json
+        );
+        assert_eq!(to_json(&[]), "[\n]\n");
+    }
+}
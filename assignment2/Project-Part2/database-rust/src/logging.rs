@@ -0,0 +1,156 @@
+/* ------logging.rs------
+ *
+ * the logger behind the log crate macros (log::info!, log::debug!, ...) used by every module,
+ * database_enhanced.c logs into it too through db_log -> db_log_message in database_wrapper.rs
+ *
+ * which lines are written is decided by a filter: a comma separated list of directives, either a
+ * level on its own (the default for every target) or target=level
+ *
+ *   info                        everything at info and above (the default)
+ *   warn,c=debug                the C backend at debug, everything else at warn
+ *   debug,database_wrapper=off  everything at debug except the wrapper
+ *
+ * levels are off, error, warn, info, debug and trace. a target is the module name without the
+ * crate (database_fix_full, database_wrapper, wal, ...), mixed_code_database for main itself and
+ * c for the C backend. a directive also covers the modules below it, the longest match wins
+ *
+ * the filter comes from the DB_LOG environment variable, --log <filter> overrides it and
+ * --quiet is the same as --log off. lines go to stdout as "[LEVEL target] message"
+ */
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+pub const ENV_VAR: &str = "DB_LOG";
+pub const DEFAULT_FILTER: &str = "info";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterError {
+    BadLevel(String),
+    EmptyTarget(String),
+}
+
+impl std::fmt::Display for FilterError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FilterError::BadLevel(level) => write!(f, "unknown log level {:?}", level),
+            FilterError::EmptyTarget(directive) => {
+                write!(f, "log directive {:?} has no target", directive)
+            }
+        }
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[derive(Debug, Clone)]
+pub struct Filter {
+    default: LevelFilter,
+    // longest target first, so the first match is the most specific one
+    targets: Vec<(String, LevelFilter)>,
+}
+
+fn parse_level(level: &str) -> Result<LevelFilter, FilterError> {
+    level
+        .trim()
+        .parse()
+        .map_err(|_| FilterError::BadLevel(level.trim().to_string()))
+}
+
+impl Filter {
+    pub fn parse(spec: &str) -> Result<Self, FilterError> {
+        let mut filter = Filter {
+            default: LevelFilter::Info,
+            targets: Vec::new(),
+        };
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    let target = target.trim();
+                    if target.is_empty() {
+                        return Err(FilterError::EmptyTarget(directive.to_string()));
+                    }
+                    filter.targets.push((target.to_string(), parse_level(level)?));
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+        filter.targets.sort_by_key(|target| std::cmp::Reverse(target.0.len()));
+        Ok(filter)
+    }
+
+    pub fn level_for(&self, target: &str) -> LevelFilter {
+        let target = short_target(target);
+        self.targets
+            .iter()
+            .find(|(name, _)| {
+                target == name
+                    || (target.starts_with(name.as_str()) && target[name.len()..].starts_with("::"))
+            })
+            .map_or(self.default, |(_, level)| *level)
+    }
+
+    // the most verbose level any target can log at, so the macros skip everything above it
+    fn max_level(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, std::cmp::max)
+    }
+}
+
+// module_path!() starts with the crate, which depends on the binary the module is built into
+fn short_target(target: &str) -> &str {
+    match target.split_once("::") {
+        Some((_, rest)) => rest,
+        None => target,
+    }
+}
+
+struct Logger {
+    filter: Filter,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level_for(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        println!(
+            "[{:<5} {}] {}",
+            record.level(),
+            short_target(record.target()),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+/// Install the logger, `spec` of None falls back to DB_LOG and then to DEFAULT_FILTER
+pub fn init(spec: Option<&str>) -> Result<(), FilterError> {
+    let spec = match spec {
+        Some(spec) => spec.to_string(),
+        None => std::env::var(ENV_VAR).unwrap_or_else(|_| DEFAULT_FILTER.to_string()),
+    };
+    let filter = Filter::parse(&spec)?;
+    log::set_max_level(filter.max_level());
+    // only fails if a logger is already installed, which then keeps logging
+    let _ = log::set_logger(Box::leak(Box::new(Logger { filter })));
+    Ok(())
+}
+
+// db_log levels are the numeric values of log::Level (LOG_ERROR = 1 ... LOG_TRACE = 5)
+pub fn level_from_c(level: i32) -> Option<Level> {
+    match level {
+        1 => Some(Level::Error),
+        2 => Some(Level::Warn),
+        3 => Some(Level::Info),
+        4 => Some(Level::Debug),
+        5 => Some(Level::Trace),
+        _ => None,
+    }
+}
@@ -73,9 +73,18 @@
  *
 */
 
+mod audit;
 mod database_fix_full;
 mod database_wrapper;
+mod email;
+mod export;
 mod generated_data;
+mod logging;
+mod password_policy;
+mod query;
+mod snapshot;
+mod username_policy;
+mod wal;
 
 struct UserEntry {
     email: Option<String>,
@@ -91,16 +100,27 @@ struct DayData {
 }
 
 const MAX_USERS: usize = 1000;
-const SESSION_TOKEN_MAX_LEN: usize = 32;
-const MAX_PASSWORD_LENGTH: usize = 100;
 
 use database_fix_full::{
-    add_user, create_user, find_user_by_username, find_user_by_username_mut, update_database_daily,
-    UserDatabase, UserStruct,
+    add_user, create_user_with_hash, find_archived_user, find_user_by_username,
+    find_user_by_username_mut, hash_password, ArchivedUser, Password, SessionToken,
+    update_database_daily, DbError, FixedThreshold, OwnershipType, PasswordHash, RetentionPolicy,
+    UserDatabase, UserRecord, UserStruct,
 };
+use database_fix_full::alloc_registry;
 use database_wrapper::{
-    initialize_enhanced_database, DatabaseExtensions, UserReference, UserStructT,
+    initialize_enhanced_database, DatabaseExtensions, MergedDuplicate, UserReference, UserStructT,
 };
+use audit::AuditEvent;
+use email::EmailError;
+use password_policy::{PasswordError, PasswordPolicy};
+use query::Query;
+use username_policy::{UsernameError, UsernamePolicy};
+use snapshot::{Snapshot, SnapshotError, SnapshotRecord, IN_C_DB, IN_RUST_DB};
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::path::Path;
+use wal::{Wal, WalRecord};
 
 /*
 pub struct UserInfoT<'a> {
@@ -114,189 +134,359 @@ pub struct UserInfoT<'a> {
 pub struct UserInfoT {
     email: String,
     username: String,
-    password: String,
+    // hashed when the signup is queued, so neither the queue nor the write ahead log holds the plaintext
+    password_hash: PasswordHash,
+}
+
+// why a signup or an account change was turned away, nothing rejected by a policy reaches either backend
+#[derive(Debug, Clone, PartialEq)]
+pub enum AccountError {
+    InvalidEmail(EmailError),
+    InvalidUsername(UsernameError),
+    InvalidPassword(PasswordError),
+    Database(DbError),
+    // the change could not be written to the write ahead log
+    Log(String),
+}
+
+impl std::fmt::Display for AccountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AccountError::InvalidEmail(e) => write!(f, "invalid email: {}", e),
+            AccountError::InvalidUsername(e) => write!(f, "invalid username: {}", e),
+            AccountError::InvalidPassword(e) => write!(f, "invalid password: {}", e),
+            AccountError::Database(e) => write!(f, "{}", e),
+            AccountError::Log(e) => write!(f, "{}", e),
+        }
+    }
 }
 
+impl std::error::Error for AccountError {}
+
 pub struct EnhancedStudentDatabase {
     rust_db: Box<UserDatabase>,
     c_extensions: DatabaseExtensions,
     user_references: Vec<UserReference>,
-    session_tokens: Vec<String>,
+    session_tokens: Vec<SessionToken>,
     pending_requests: Vec<UserInfoT>,
     _day_counter: Box<i32>,
     c_allocated_users: Vec<i32>,
+    wal: Option<Wal>,
+    username_policy: UsernamePolicy,
+    password_policy: PasswordPolicy,
+}
+
+// appends to the write ahead log if one is enabled, called before the mutation is applied
+fn log_wal(wal: &mut Option<Wal>, record: WalRecord) -> Result<(), String> {
+    match wal {
+        Some(wal) => wal.append(&record).map_err(|e| e.to_string()),
+        None => Ok(()),
+    }
+}
+
+// adds the outcome of an operation to its audit event and records it
+fn audit_outcome<T, E: std::fmt::Display>(event: AuditEvent, result: &Result<T, E>) {
+    let event = match result {
+        Ok(_) => event.field("outcome", "success"),
+        Err(e) => event.field("outcome", "failure").field("reason", e.to_string()),
+    };
+    audit::record(event);
+}
+
+// UserDatabase::on_user_removed for the rust database
+fn rust_user_removed(archived: &ArchivedUser) {
+    database_wrapper::forget_c_reference(&archived.user);
+    let database_fix_full::RemovalReason::Inactivity { days } = archived.tombstone.reason;
+    audit::record(
+        AuditEvent::new(archived.tombstone.removed_on_day, "inactivity_removal")
+            .user(Some(archived.user.user_id), archived.user.username.as_str())
+            .field("backend", "rust")
+            .field("inactive_days", days),
+    );
 }
 
 pub fn str_cmp(a: &[u8], b: &str) -> bool {
     let a_str = std::str::from_utf8(a).unwrap_or("");
     a_str.trim_end_matches(char::from(0)) == b
 }
-pub fn bytes_to_string(bytes: &[u8]) -> String {
-    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
-    String::from_utf8_lossy(&bytes[..end]).to_string()
-}
-pub fn string_to_bytes(s: String) -> [u8; SESSION_TOKEN_MAX_LEN] {
-    let mut byte_array = [0u8; SESSION_TOKEN_MAX_LEN];
-    let bytes = s.as_bytes();
-    let len = bytes.len().min(SESSION_TOKEN_MAX_LEN - 1);
-    byte_array[..len].copy_from_slice(&bytes[..len]);
-    byte_array[len] = 0;
-    byte_array
-}
 
 impl EnhancedStudentDatabase {
     /// Initialize a new enhanced database instance
     pub fn new() -> Self {
-        println!("Initializing Enhanced Student Database System...");
+        log::info!("Initializing Enhanced Student Database System...");
         let dc = Box::new(0);
-        let c_extensions = initialize_enhanced_database(&*dc);
+        // rust_db is boxed, so the id counter the C backend points to stays put (same as the day counter)
+        let mut rust_db = database_fix_full::init_database();
+        let c_extensions = initialize_enhanced_database(&dc, &mut rust_db.id_allocator);
+        rust_db.on_user_removed = Some(rust_user_removed);
 
         let std_b = EnhancedStudentDatabase {
-            rust_db: database_fix_full::init_database(),
+            rust_db,
             user_references: Vec::new(),
             session_tokens: Vec::new(),
             pending_requests: Vec::new(),
             _day_counter: dc,
             c_extensions,
             c_allocated_users: Vec::new(),
+            wal: None,
+            username_policy: UsernamePolicy::default(),
+            password_policy: PasswordPolicy::default(),
         };
+        alloc_registry::set_day(std_b.processing_day());
         std_b
     }
     pub fn enqueue_user(
         &mut self,
         username: String,
         email: String,
-        password: String,
-    ) -> Result<(), String> {
+        password: Password,
+    ) -> Result<(), AccountError> {
+        let day = self.processing_day();
+        let event = AuditEvent::new(day, "signup_requested").user(None, &username);
+        let result = self.queue_signup(username, email, password);
+        audit_outcome(event, &result);
+        result
+    }
+
+    fn queue_signup(
+        &mut self,
+        username: String,
+        email: String,
+        password: Password,
+    ) -> Result<(), AccountError> {
+        self.username_policy
+            .check(&username)
+            .map_err(AccountError::InvalidUsername)?;
+        let email = email::normalize_email(&email).map_err(AccountError::InvalidEmail)?;
+        self.password_policy
+            .check(password.expose())
+            .map_err(AccountError::InvalidPassword)?;
+        let password_hash = hash_password(&password).map_err(AccountError::Database)?;
+        let day = self.processing_day();
+        log_wal(
+            &mut self.wal,
+            WalRecord::Signup {
+                day,
+                username: username.clone(),
+                email: email.clone(),
+                password_hash: password_hash.clone(),
+            },
+        )
+        .map_err(AccountError::Log)?;
         let user_info = UserInfoT {
             email,
             username,
-            password,
+            password_hash,
         };
         self.pending_requests.push(user_info);
         Ok(())
     }
 
+    pub fn set_username_policy(&mut self, policy: UsernamePolicy) {
+        self.username_policy = policy;
+    }
+
+    pub fn set_password_policy(&mut self, policy: PasswordPolicy) {
+        self.password_policy = policy;
+    }
+
+    // evaluated by both backends at the end of each day (the C one through db_retention_decide)
+    pub fn set_retention_policy(&mut self, policy: Box<dyn RetentionPolicy>) {
+        self.rust_db.retention_policy = policy;
+    }
+
+    // the day whose activity is being recorded, increase_day moves the counter onto it
+    fn processing_day(&self) -> i32 {
+        *self._day_counter + 1
+    }
+
+    // for audit events, whichever backend has the user
+    fn user_id_of(&self, user_name: &str) -> Option<i32> {
+        if let Some(user) = find_user_by_username(&self.rust_db, user_name) {
+            return Some(user.user_id);
+        }
+        let user = self.c_extensions.get_user_in_c_backend(user_name);
+        if user.is_null() {
+            return None;
+        }
+        Some(self.c_extensions.read_user(user).user_id)
+    }
+
     // Read Only : Dont Change
+    // (pending signups carry the password hash instead of the plaintext)
     pub fn sync_database(&mut self) {
         //Signup all pending users
         let drained_users: Vec<_> = self.pending_requests.drain(..).collect();
         for (_i, user) in drained_users.iter().enumerate() {
             let pending_count = drained_users.len() - _i;
-            println!("[RUST] ADDING USER WITH SYNC: {:?}", user.username);
-            let _ =
-                self.add_user_with_sync(&user.username, &user.email, &user.password, pending_count);
+            log::trace!("ADDING USER WITH SYNC: {:?}", user.username);
+            let _ = self.add_user_with_sync(
+                &user.username,
+                &user.email,
+                &user.password_hash,
+                pending_count,
+            );
         }
     }
     // Read Only : Dont Change
+    // (user_login now returns a Result - a login for a user that was just found cannot fail,
+    // so the error is only logged)
     pub fn activate_user(&mut self, user_name: &str) {
         // println!("Activating user with ID: {}", user_id);
-        database_fix_full::user_login(&mut self.rust_db, user_name);
+        if let Err(e) = database_fix_full::user_login(&mut self.rust_db, user_name) {
+            log::warn!("ACTIVATE USER FAILED: {}", e);
+        }
     }
     // Read Only : Dont Change
+    // (changed so the DbError from add_user is reported to the caller instead of being dropped,
+    // and to take the password hash computed when the signup was queued)
+    // (both allocator paths write a signup audit event, this is the only place that knows which one was taken)
     pub fn add_user_with_sync(
         &mut self,
         username: &str,
         email: &str,
-        password: &str,
+        password_hash: &PasswordHash,
         pending_count: usize,
     ) -> Result<(), String> {
         // Intelligent load balancing - use C allocator when under pressure
-        println!("[RUST] ADD_USERS_WITH_SYNC");
+        log::trace!("ADD_USERS_WITH_SYNC");
+        // each backend only checks its own users, a name taken in the other one is taken too
+        if self.username_taken(username) {
+            let event = AuditEvent::new(self.processing_day(), "signup").user(None, username);
+            let result: Result<(), DbError> = Err(DbError::DuplicateUsername);
+            audit_outcome(event, &result);
+            return result.map_err(|e| e.to_string());
+        }
+        // the only time the username decides the role, a later rename keeps it
+        let role = self.rust_db.retention_policy.role_at_signup(username);
         if pending_count > 5 || self.rust_db.count >= MAX_USERS as i32 {
-            println!(
-                "[System] High load detected, using optimized C allocator for user {}",
+            log::debug!(
+                "High load detected, using optimized C allocator for user {}",
                 username
             );
-            println!("[RUST] SYNCING USER TO C BACKEND");
-            self.c_extensions
-                .sync_user_to_c_backend(username, email, 0, password)?;
+            log::trace!("SYNCING USER TO C BACKEND");
+            let event = AuditEvent::new(self.processing_day(), "signup").field("backend", "c");
+            let result = self
+                .c_extensions
+                .sync_user_to_c_backend(username, email, 0, password_hash, role);
+            let id = result.as_ref().ok().map(|_| self.c_extensions.get_last_user_id());
+            audit_outcome(event.user(id, username), &result);
+            result?;
             let id = self.c_extensions.get_last_user_id();
             self.c_allocated_users.push(id);
             return Ok(());
         }
 
-        println!("[RUST] CREATING USER: {:?}", username);
-        let user = create_user(username, email, 0, password);
-        println!("[RUST] ADDING USER: {:?}", username);
-        add_user(&mut self.rust_db, user);
+        log::trace!("CREATING USER: {:?}", username);
+        let mut user = create_user_with_hash(username, email, 0, password_hash.clone());
+        user.role = role;
+        log::trace!("ADDING USER: {:?}", username);
+        let event = AuditEvent::new(self.processing_day(), "signup").field("backend", "rust");
+        let result = add_user(&mut self.rust_db, user);
+        let id = find_user_by_username(&self.rust_db, username).map(|user| user.user_id);
+        audit_outcome(event.user(id.filter(|_| result.is_ok()), username), &result);
+        result.map_err(|e| e.to_string())?;
 
-        println!(
-            "[System] Added user {} using dual allocation strategy",
+        log::debug!(
+            "Added user {} using dual allocation strategy",
             username
         );
         Ok(())
     }
 
+    // both backends compare usernames ignoring ASCII case (normalize_username / strcasecmp)
+    fn username_taken(&self, username: &str) -> bool {
+        find_user_by_username(&self.rust_db, username).is_some()
+            || !self.c_extensions.get_user_in_c_backend(username).is_null()
+    }
+
     pub fn find_user_by_name<'a>(
         &self,
         db: &'a UserDatabase,
-        username: &str,
+        username: &'a str,
     ) -> Option<&'a UserStruct> {
-        for i in 0..db.count as usize {
-            if let Some(ref user) = db.users[i] {
-                if str_cmp(&user.username, username) {
-                    return Some(user);
-                }
-            }
-        }
-        None
+        // same lookup rules as the rest of the rust database (normalized username index)
+        find_user_by_username(db, username)
     }
-    fn update_user_session_token(&mut self, user_name: &str, token: String) {
+    fn update_user_session_token(&mut self, user_name: &str, token: SessionToken) {
         if let Some(user) = find_user_by_username_mut(&mut self.rust_db, user_name) {
-            user.session_token = string_to_bytes(token.clone());
+            user.session_token = token.clone();
             if !self.session_tokens.contains(&token) {
                 self.session_tokens.push(token);
             }
         }
     }
+    /// Authenticate user and create session, every attempt is audited with its outcome
+    pub fn login_user(&mut self, user_name: &str, password: &Password) -> Result<SessionToken, String> {
+        let day = self.processing_day();
+        let result = self.authenticate_user(user_name, password);
+        let event = AuditEvent::new(day, "login").user(self.user_id_of(user_name), user_name);
+        audit_outcome(event, &result);
+        result
+    }
+
     /// Read Only: Dont Modify Authenticate user and create session
-    pub fn login_user(&mut self, user_name: &str, password: &str) -> Result<String, String> {
-        println!("[RUST] LOGIN USER CALLED FOR USER: {:?}", user_name);
+    /// (password checks changed to verify against the stored hash - plaintext passwords can no longer be read back,
+    /// successful logins are written to the write ahead log before the user is touched)
+    /// (renamed from login_user, which now wraps it to write the audit event for every outcome)
+    /// (user_references hold C handles instead of pointers - references to users C has freed since are dropped first)
+    fn authenticate_user(&mut self, user_name: &str, password: &Password) -> Result<SessionToken, String> {
+        log::debug!("LOGIN USER CALLED FOR USER: {:?}", user_name);
         if self.find_user_by_name(&self.rust_db, user_name).is_none() {
-            println!("[RUST] USER NOT FOUND IN RUST DB - EXISTS IN C DB\n");
+            log::trace!("USER NOT FOUND IN RUST DB - EXISTS IN C DB");
+            self.prune_user_references();
             // User found in C backend cache
             for user_ref in self.user_references.iter_mut() {
-                println!("[RUST] ACCESSING USER REFERENCE: {:?}", user_ref.username);
+                log::trace!("ACCESSING USER REFERENCE: {:?}", user_ref.username);
                 if str_cmp((*user_ref).username.as_bytes(), user_name) {
-                    println!(
+                    log::trace!(
                         "USERNAMES MATCH: {:?} vs {:?}",
                         (*user_ref).username.as_bytes(),
                         user_name
                     );
-                    if self.c_extensions.get_user_password(user_ref.ptr) != password {
+                    let user_ptr = match self.c_extensions.resolve_handle(user_ref.handle) {
+                        Some(user_ptr) => user_ptr,
+                        None => continue,
+                    };
+                    if !self.c_extensions.verify_user_password(user_ptr, password) {
                         return Err("Incorrect password".to_string());
                     }
-                    println!("[RUST] RESETTING USER REFERENCE LOGIN FIELDS!");
+                    log_wal(
+                        &mut self.wal,
+                        WalRecord::Login {
+                            day: *self._day_counter + 1,
+                            username: user_name.to_string(),
+                        },
+                    )?;
+                    log::trace!("RESETTING USER REFERENCE LOGIN FIELDS!");
                     unsafe {
-                        (*user_ref.ptr).inactivity_count = 0;
-                        (*user_ref.ptr).is_active = 1;
+                        (*user_ptr).inactivity_count = 0;
+                        (*user_ptr).is_active = 1;
                     }
-                    let session_token = self.c_extensions.create_session_for_c_ptr(user_ref.ptr)?;
+                    let session_token = self.c_extensions.create_session_for_c_ptr(user_ptr)?;
                     return Ok(session_token);
                 }
             }
 
-            println!("[RUST] FETCHING USER FROM C BACKEND");
+            log::trace!("FETCHING USER FROM C BACKEND");
             let user = self.c_extensions.get_user_in_c_backend(user_name);
-            println!("[RUST] USER FROM C BACKEND: {:?}", user);
+            log::trace!("USER FROM C BACKEND: {:?}", user);
             if user == std::ptr::null_mut() {
                 return Err("User not found in any backend".to_string());
             }
 
-            self.user_references
-                .push(UserReference::new(String::from(user_name), user));
-
-            let user_password = self.c_extensions.get_user_password(user);
+            self.cache_user_reference(user_name, user);
 
-            if user_password == password {
+            if self.c_extensions.verify_user_password(user, password) {
+                self.log_login(user_name)?;
                 return self.c_extensions.login_user(user_name);
             } else {
                 return Err("Incorrect password".to_string());
             }
         } else {
-            let user = find_user_by_username(&self.rust_db, user_name).unwrap();
-            if str_cmp(&user.password, password) {
+            if database_fix_full::verify_password(&self.rust_db, user_name, password) {
+                self.log_login(user_name)?;
+                let user = find_user_by_username(&self.rust_db, user_name).unwrap();
                 // println!("User[{}] {} logged in successfully", user.user_id, user_name);
                 let session_token = self.c_extensions.create_session(user)?;
                 self.update_user_session_token(user_name, session_token.clone());
@@ -308,81 +498,507 @@ impl EnhancedStudentDatabase {
         }
     }
 
+    // a reference whose handle no longer resolves can never be used again
+    fn prune_user_references(&mut self) {
+        let c_extensions = &self.c_extensions;
+        self.user_references
+            .retain(|user_ref| c_extensions.resolve_handle(user_ref.handle).is_some());
+    }
+
+    // references cached for a user C merged duplicates of may be to the alias it dropped, so they all
+    // go - the next login caches the alias C kept
+    fn forget_merged_users(&mut self, merged: &[MergedDuplicate]) {
+        if merged.is_empty() {
+            return;
+        }
+        let before = self.user_references.len();
+        self.user_references
+            .retain(|user_ref| !merged.iter().any(|m| m.username == user_ref.username));
+        log::debug!(
+            "DROPPED {} CACHED REFERENCES TO MERGED USERS",
+            before - self.user_references.len()
+        );
+    }
+
+    fn cache_user_reference(&mut self, user_name: &str, user: *mut UserStructT) {
+        if let Some(handle) = self.c_extensions.issue_handle(user) {
+            self.user_references
+                .push(UserReference::new(String::from(user_name), handle));
+        }
+    }
+
+    fn log_login(&mut self, user_name: &str) -> Result<(), String> {
+        let day = self.processing_day();
+        log_wal(
+            &mut self.wal,
+            WalRecord::Login {
+                day,
+                username: user_name.to_string(),
+            },
+        )
+    }
+
+    pub fn update_username(
+        &mut self,
+        user_name: &str,
+        new_user_name: &str,
+    ) -> Result<(), AccountError> {
+        let day = self.processing_day();
+        let result = self.rename_user(user_name, new_user_name);
+        let current_name = if result.is_ok() { new_user_name } else { user_name };
+        let event = AuditEvent::new(day, "username_changed")
+            .user(self.user_id_of(current_name), user_name)
+            .field("new_username", new_user_name);
+        audit_outcome(event, &result);
+        result
+    }
+
+    fn rename_user(&mut self, user_name: &str, new_user_name: &str) -> Result<(), AccountError> {
+        self.username_policy
+            .check(new_user_name)
+            .map_err(AccountError::InvalidUsername)?;
+        let day = self.processing_day();
+        log_wal(
+            &mut self.wal,
+            WalRecord::Rename {
+                day,
+                username: user_name.to_string(),
+                new_username: new_user_name.to_string(),
+            },
+        )
+        .map_err(AccountError::Log)?;
+        database_fix_full::update_username(&mut self.rust_db, user_name, new_user_name)
+            .map_err(AccountError::Database)
+    }
+
+    pub fn update_password(&mut self, user_name: &str, password: &Password) -> Result<(), AccountError> {
+        let day = self.processing_day();
+        let result = self.change_password(user_name, password);
+        let event = AuditEvent::new(day, "password_changed").user(self.user_id_of(user_name), user_name);
+        audit_outcome(event, &result);
+        result
+    }
+
+    fn change_password(&mut self, user_name: &str, password: &Password) -> Result<(), AccountError> {
+        self.password_policy
+            .check(password.expose())
+            .map_err(AccountError::InvalidPassword)?;
+        let password_hash = hash_password(password).map_err(AccountError::Database)?;
+        let day = self.processing_day();
+        log_wal(
+            &mut self.wal,
+            WalRecord::ChangePassword {
+                day,
+                username: user_name.to_string(),
+                password_hash: password_hash.clone(),
+            },
+        )
+        .map_err(AccountError::Log)?;
+        database_fix_full::update_password_hash(&mut self.rust_db, user_name, password_hash)
+            .map_err(AccountError::Database)
+    }
+
+    /// Bring back a user removed for inactivity, with the user_id and ownership they had
+    pub fn restore_user(&mut self, user_name: &str) -> Result<(), AccountError> {
+        let day = self.processing_day();
+        let user_id = find_archived_user(&self.rust_db, user_name).map(|archived| archived.user.user_id);
+        let result = self.restore_archived(user_name);
+        audit_outcome(AuditEvent::new(day, "user_restored").user(user_id, user_name), &result);
+        result
+    }
+
+    fn restore_archived(&mut self, user_name: &str) -> Result<(), AccountError> {
+        if find_archived_user(&self.rust_db, user_name).is_none() {
+            return Err(AccountError::Database(DbError::NotFound));
+        }
+        // the name may have been taken by a new signup in either backend since
+        if self.username_taken(user_name) {
+            return Err(AccountError::Database(DbError::DuplicateUsername));
+        }
+        let day = self.processing_day();
+        log_wal(
+            &mut self.wal,
+            WalRecord::Restore {
+                day,
+                username: user_name.to_string(),
+            },
+        )
+        .map_err(AccountError::Log)?;
+        self.apply_restore(user_name).map_err(AccountError::Database)
+    }
+
+    // users the C backend removed go back to the C backend, the rest to rust
+    fn apply_restore(&mut self, user_name: &str) -> Result<(), DbError> {
+        let archived = match find_archived_user(&self.rust_db, user_name) {
+            Some(archived) => archived,
+            None => return Err(DbError::NotFound),
+        };
+        if archived.user.ownership() != OwnershipType::C_OWNED {
+            return database_fix_full::restore_user(&mut self.rust_db, user_name);
+        }
+        let mut archived = match database_fix_full::take_archived_user(&mut self.rust_db, user_name) {
+            Some(archived) => archived,
+            None => return Err(DbError::NotFound),
+        };
+        archived.user.inactivity_count = 0;
+        archived.user.is_active = 1;
+        if let Err(e) = self.c_extensions.restore_c_user(&archived.user, true) {
+            log::error!("RESTORING {} INTO THE C BACKEND FAILED: {}", user_name, e);
+            self.rust_db.archive.push(archived);
+            return Err(DbError::Full);
+        }
+        log::info!("RESTORED C USER {}", user_name);
+        Ok(())
+    }
+
     // Read Only : Dont Change
+    // (add_user now returns a Result, failures are logged instead of silently ignored,
+    // and users are found through iter_users since they can be in any slot of rust_db.users)
+    // (writes a join_databases audit event with the number of users shared each way)
     pub fn join_databases(&mut self) {
         //Creating shared handles for all users in Rust DB
-        print!(
-            "[Info] Creating shared handles for {} rust users\n",
-            (*self.rust_db).count
-        );
+        log::info!("Creating shared handles for {} rust users", self.rust_db.count);
         // Sync all users from Rust DB to C backend
-        // iterates over rust_db.users for rust_db.count iterations
-        for user_opt in self.rust_db.users.iter().take(self.rust_db.count as usize) {
-            if user_opt.is_none() {
-                continue;
-            }
-            if let Some(user) = user_opt {
-                let user_ptr = {
-                    let ptr = std::ptr::addr_of!(**user);
-                    ptr as *mut UserStructT
-                };
-                println!("[RUST:JOIN_DATABASES] SYNCING USERS FROM RUST DB");
-                self.c_extensions.sync_user_from_rust_db(user_ptr);
-            }
+        // iterates over the occupied slots of rust_db.users (users are not packed below count)
+        for user in database_fix_full::iter_users(&self.rust_db) {
+            let user_ptr = {
+                let ptr = std::ptr::addr_of!(**user);
+                ptr as *mut UserStructT
+            };
+            log::trace!("SYNCING USERS FROM RUST DB");
+            self.c_extensions.sync_user_from_rust_db(user_ptr);
         }
 
         // Now perform the complementary sync from C backend to Rust DB
-        println!("[Info] Syncing all user references from C backend...");
+        log::info!("Syncing all user references from C backend...");
 
         // Get pointer references for C users and extend local references
-        println!("[RUST:JOIN_DATABASES]] GETTING ALL USER REFERENCES FROM C");
+        log::trace!("GETTING ALL USER REFERENCES FROM C");
         let all_c_userstructs = self.c_extensions.get_all_user_references();
+        audit::record(
+            AuditEvent::new(*self._day_counter, "join_databases")
+                .field("rust_users_shared", self.rust_db.count)
+                .field("c_users_shared", all_c_userstructs.len()),
+        );
         // add all users in this vector to rust db
-        println!("[RUST:JOIN_DATABASES]] ADDING ALL USERS FROM C BACKEND");
+        log::trace!("ADDING ALL USERS FROM C BACKEND");
         for user in all_c_userstructs {
-            println!(
-                "[RUST:JOIN_DATABASES]] ADDING: {:?} FRON C BACKEND TO RUST DB",
+            log::trace!(
+                "ADDING: {:?} FRON C BACKEND TO RUST DB",
                 user.username
             );
-            add_user(&mut self.rust_db, user);
+            if let Err(e) = add_user(&mut self.rust_db, user) {
+                log::warn!("FAILED TO ADD USER: {}", e);
+            }
         }
     }
 
     pub fn validate_active_user_session(&self) {
         // Take all users in this database and validate their sessions in C backend
-        for user in self.rust_db.users.iter().take(self.rust_db.count as usize) {
-            if let Some(u) = user {
-                if u.is_active == 1 {
-                    let _ = self
-                        .c_extensions
-                        .validate_session(bytes_to_string(&u.session_token).as_str());
-                }
+        for u in database_fix_full::iter_users(&self.rust_db) {
+            if u.is_active == 1 {
+                let _ = self
+                    .c_extensions
+                    .validate_session(&u.session_token);
             }
         }
     }
     //Read Only : Dont Change
-    pub fn increase_day(&mut self) {
+    // (the end of the day is written to the write ahead log first - if that fails the day cannot be
+    // made durable, so nothing is changed and the error is returned, recovery picks up from the
+    // last snapshot)
+    // (the C backend is given rust_db mutably, users it removes are copied into rust_db's archive)
+    pub fn increase_day(&mut self) -> Result<(), AccountError> {
+        let day = self.processing_day();
+        log_wal(&mut self.wal, WalRecord::EndOfDay { day }).map_err(AccountError::Log)?;
         //Resolve all signup requests
-        println!("[RUST] SYNCING DB!\n");
+        log::trace!("SYNCING DB!");
         self.sync_database();
         // Increment the day counter
         *(self._day_counter) += 1;
         // Validate active user sessions
-        println!("[RUST] VALIDATING USER SESSION!\n");
+        log::trace!("VALIDATING USER SESSION!");
         self.validate_active_user_session();
         // Update rust database (uses the function you translated for Part 1)
-        println!("[RUST] UPDATING DB!\n");
+        log::trace!("UPDATING DB!");
         update_database_daily(&mut self.rust_db);
         // Every 5 days, join the two databases
 
         if *(self._day_counter) % 5 == 0 {
-            println!("[RUST] JOINING DATABASES!");
+            log::debug!("JOINING DATABASES!");
             self.join_databases();
         }
 
         // Perform daily updates on C backend
-        println!("[RUST] CALLING C SIDE INCREMENTATION");
-        self.c_extensions.increment_day(&self.rust_db);
+        log::trace!("CALLING C SIDE INCREMENTATION");
+        let merged = self.c_extensions.increment_day(&mut self.rust_db);
+        self.forget_merged_users(&merged);
+        alloc_registry::set_day(self.processing_day());
+        Ok(())
+    }
+
+    /// Read-only query over the users of both backends (see query.rs)
+    pub fn query(&self) -> Query<'_> {
+        Query::new(&self.rust_db, &self.c_extensions)
+    }
+
+    /// Write every user of both backends to `path` as JSON, one row per backend (see export.rs)
+    pub fn export_json<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, export::to_json(&self.query().run()))
+    }
+
+    /// Same rows as export_json, as CSV with a header line
+    pub fn export_csv<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        std::fs::write(path, export::to_csv(&self.query().run()))
+    }
+
+    pub fn current_day(&self) -> i32 {
+        *self._day_counter
+    }
+
+    /// Save both databases to `path`, every allocation is written once with the databases that hold it
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<(), SnapshotError> {
+        let c_pointers = self.c_extensions.c_user_pointers();
+        let in_c: HashSet<usize> = c_pointers.iter().map(|ptr| *ptr as usize).collect();
+        let mut seen: HashSet<usize> = HashSet::new();
+        let mut records = Vec::new();
+
+        for user in database_fix_full::iter_users(&self.rust_db) {
+            let addr = std::ptr::addr_of!(**user) as usize;
+            let mut backends = IN_RUST_DB;
+            if in_c.contains(&addr) {
+                backends |= IN_C_DB;
+            }
+            seen.insert(addr);
+            records.push(SnapshotRecord {
+                backends,
+                user: (**user).clone(),
+            });
+        }
+        for ptr in c_pointers {
+            if !seen.insert(ptr as usize) {
+                continue;
+            }
+            records.push(SnapshotRecord {
+                backends: IN_C_DB,
+                user: self.c_extensions.read_user(ptr),
+            });
+        }
+
+        let snapshot = Snapshot {
+            day: *self._day_counter,
+            next_user_id: self.rust_db.id_allocator.next_user_id,
+            c_allocated_users: self.c_allocated_users.clone(),
+            records,
+            archive: Cow::Borrowed(&self.rust_db.archive),
+        };
+        snapshot::write_file(path.as_ref(), &snapshot)?;
+        // everything in the write ahead log is covered by the snapshot now
+        if let Some(wal) = &self.wal {
+            if let Err(e) = wal.checkpoint() {
+                log::error!("Failed to truncate write ahead log: {}", e);
+            }
+        }
+        log::info!(
+            "SNAPSHOT SAVED: DAY {} - {} USERS",
+            snapshot.day,
+            snapshot.records.len()
+        );
+        Ok(())
+    }
+
+    /// Build a database from a snapshot written by save_snapshot, users are re-allocated by the side
+    /// that owned them and re-shared with the other database if they were shared when saved
+    pub fn load_snapshot<P: AsRef<Path>>(path: P) -> Result<Self, SnapshotError> {
+        let snapshot = snapshot::read_file(path.as_ref())?;
+        let mut db = EnhancedStudentDatabase::new();
+        *db._day_counter = snapshot.day;
+        alloc_registry::set_day(db.processing_day());
+
+        for record in snapshot.records {
+            let in_rust = record.backends & IN_RUST_DB != 0;
+            let in_c = record.backends & IN_C_DB != 0;
+            let rust_allocated = matches!(
+                record.user.ownership(),
+                OwnershipType::RUST_OWNED | OwnershipType::SHARED_C_PRIMARY
+            );
+            if in_rust && rust_allocated {
+                let user = UserRecord::new(record.user);
+                let user_ptr = user.as_ptr() as *mut UserStructT;
+                add_user(&mut db.rust_db, user).map_err(|e| SnapshotError::Restore(e.to_string()))?;
+                if in_c {
+                    db.c_extensions
+                        .add_user_reference(user_ptr)
+                        .map_err(SnapshotError::Restore)?;
+                }
+            } else {
+                let user_ptr = db
+                    .c_extensions
+                    .restore_c_user(&record.user, in_c)
+                    .map_err(SnapshotError::Restore)?;
+                if in_rust {
+                    // in_c: the C database holds the reference restore_c_user made, rust needs one too
+                    let user = if in_c {
+                        db.c_extensions.share_c_user(user_ptr)
+                    } else {
+                        db.c_extensions.box_c_user(user_ptr)
+                    };
+                    add_user(&mut db.rust_db, user)
+                        .map_err(|e| SnapshotError::Restore(e.to_string()))?;
+                }
+            }
+        }
+
+        let next_user_id = db.rust_db.id_allocator.next_user_id;
+        db.rust_db.id_allocator.next_user_id = next_user_id.max(snapshot.next_user_id);
+        db.c_allocated_users = snapshot.c_allocated_users;
+        db.rust_db.day = snapshot.day;
+        db.rust_db.archive = snapshot.archive.into_owned();
+        log::info!("SNAPSHOT LOADED: RESUMING AFTER DAY {}", snapshot.day);
+        Ok(db)
+    }
+
+    /// Rebuild the databases after a crash: start from the snapshot (or an empty database), replay the
+    /// finished days in the write ahead log at `wal_path`, then keep logging to it
+    // retention_policy is set before replaying, so the replayed days remove the same users again
+    pub fn recover<P: AsRef<Path>>(
+        snapshot: Option<P>,
+        wal_path: P,
+        retention_policy: Box<dyn RetentionPolicy>,
+    ) -> Result<Self, String> {
+        let mut db = match snapshot {
+            Some(path) => {
+                EnhancedStudentDatabase::load_snapshot(path).map_err(|e| e.to_string())?
+            }
+            None => EnhancedStudentDatabase::new(),
+        };
+        db.set_retention_policy(retention_policy);
+        let wal_path = wal_path.as_ref();
+        let records = wal::read_file(wal_path).map_err(|e| e.to_string())?;
+        let records = wal::committed(records, db.current_day());
+        if let Some(first) = records.first() {
+            if first.day() != db.processing_day() {
+                return Err(format!(
+                    "write ahead log starts at day {} but the snapshot ends at day {}",
+                    first.day(),
+                    db.current_day()
+                ));
+            }
+        }
+        log::info!(
+            "WAL: REPLAYING {} RECORDS FROM {}",
+            records.len(),
+            wal_path.display()
+        );
+        // everything replayed was audited when it first happened
+        let suspend = audit::Suspend::new();
+        for record in &records {
+            db.replay(record)?;
+        }
+        drop(suspend);
+        // rewritten with only the replayed records so the dropped tail is not replayed next time
+        let wal = Wal::create(wal_path, &records).map_err(|e| e.to_string())?;
+        log::info!(
+            "WAL: RECOVERED TO DAY {}, LOGGING TO {}",
+            db.current_day(),
+            wal.path().display()
+        );
+        db.wal = Some(wal);
+        Ok(db)
+    }
+
+    // applies a logged mutation without logging it again (the wal is not enabled while replaying)
+    fn replay(&mut self, record: &WalRecord) -> Result<(), String> {
+        match record {
+            WalRecord::Signup {
+                username,
+                email,
+                password_hash,
+                ..
+            } => {
+                self.pending_requests.push(UserInfoT {
+                    email: email.clone(),
+                    username: username.clone(),
+                    password_hash: password_hash.clone(),
+                });
+            }
+            WalRecord::Login { username, .. } => {
+                self.replay_login(username)?;
+            }
+            // these were logged before being checked against the database, so they fail the same way again
+            WalRecord::Rename {
+                username,
+                new_username,
+                ..
+            } => {
+                if let Err(e) =
+                    database_fix_full::update_username(&mut self.rust_db, username, new_username)
+                {
+                    log::warn!("WAL: RENAME OF {} FAILED AGAIN: {}", username, e);
+                }
+            }
+            WalRecord::ChangePassword {
+                username,
+                password_hash,
+                ..
+            } => {
+                if let Err(e) = database_fix_full::update_password_hash(
+                    &mut self.rust_db,
+                    username,
+                    password_hash.clone(),
+                ) {
+                    log::warn!("WAL: PASSWORD CHANGE OF {} FAILED AGAIN: {}", username, e);
+                }
+            }
+            WalRecord::Restore { username, .. } => {
+                if let Err(e) = self.apply_restore(username) {
+                    log::warn!("WAL: RESTORE OF {} FAILED AGAIN: {}", username, e);
+                }
+            }
+            WalRecord::EndOfDay { day } => {
+                if *day != self.processing_day() {
+                    return Err(format!(
+                        "write ahead log ends day {} while replaying day {}",
+                        day,
+                        self.processing_day()
+                    ));
+                }
+                self.increase_day().map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    // the same state changes as login_user after the password check passed
+    fn replay_login(&mut self, user_name: &str) -> Result<(), String> {
+        if let Some(user) = find_user_by_username(&self.rust_db, user_name) {
+            let session_token = self.c_extensions.create_session(user)?;
+            self.update_user_session_token(user_name, session_token);
+            self.activate_user(user_name);
+            return Ok(());
+        }
+        self.prune_user_references();
+        for user_ref in self.user_references.iter() {
+            if str_cmp(user_ref.username.as_bytes(), user_name) {
+                let user_ptr = match self.c_extensions.resolve_handle(user_ref.handle) {
+                    Some(user_ptr) => user_ptr,
+                    None => continue,
+                };
+                unsafe {
+                    (*user_ptr).inactivity_count = 0;
+                    (*user_ptr).is_active = 1;
+                }
+                self.c_extensions.create_session_for_c_ptr(user_ptr)?;
+                return Ok(());
+            }
+        }
+        let user = self.c_extensions.get_user_in_c_backend(user_name);
+        if user.is_null() {
+            return Err(format!("logged in user {} not found while replaying", user_name));
+        }
+        self.cache_user_reference(user_name, user);
+        self.c_extensions.login_user(user_name)?;
+        Ok(())
     }
 
     pub fn print_both_databases(&self) {
@@ -396,28 +1012,132 @@ impl EnhancedStudentDatabase {
 fn main() {
     println!("=======Mixed Code Student Database System========");
 
-    let mut db = EnhancedStudentDatabase::new();
+    // --snapshot <file>: save both databases after every day
+    // --resume <file>:   start from a saved snapshot and skip the days it already covers
+    // --wal <file>:      log every change before applying it and replay the log on startup
+    //                    (starting from --resume, or from the --snapshot file if it exists)
+    // --audit <file>:    append account events to an audit trail, one JSON object per line
+    // --log <filter>:    which log lines to write, e.g. "warn,c=debug" (see logging.rs, default DB_LOG)
+    // --quiet:           no log lines, only the daily traffic summary
+    // --export-json <file>, --export-csv <file>: dump both databases at the end of the simulation
+    // --retention <policy>: who the daily update removes, fixed:<days> (fixed:5 is the default),
+    //                    grace:<days>:<grace_days> or roles:<role>=<days>,...;<user>=<role>,...
+    //                    (see parse_retention_policy in database_fix_full.rs)
+    // --password-classes <n>: how many of lowercase, uppercase, digits and symbols a password
+    //                    needs (2 by default), most generated passwords only use one class so
+    //                    --password-classes 1 lets enough signups through to use the C allocator
+    // --track-allocations: record every user allocation and print a report of leaks, double frees
+    //                    and frees of referenced users at the end (see alloc_registry.rs)
+    let args: Vec<String> = std::env::args().collect();
+    let arg_value = |flag: &str| {
+        args.iter()
+            .position(|a| a == flag)
+            .and_then(|i| args.get(i + 1))
+            .cloned()
+    };
+    let log_filter = if args.iter().any(|a| a == "--quiet") {
+        Some("off".to_string())
+    } else {
+        arg_value("--log")
+    };
+    if let Err(e) = logging::init(log_filter.as_deref()) {
+        println!("[Error] Invalid log filter: {}", e);
+        return;
+    }
+    let snapshot_path = arg_value("--snapshot");
+    if args.iter().any(|a| a == "--track-allocations") {
+        alloc_registry::enable();
+    }
+
+    if let Some(path) = arg_value("--audit") {
+        if let Err(e) = audit::open(&path) {
+            println!("[Error] Could not open audit log {}: {}", path, e);
+            return;
+        }
+    }
+
+    let retention_policy = match arg_value("--retention") {
+        Some(spec) => match database_fix_full::parse_retention_policy(&spec) {
+            Ok(policy) => policy,
+            Err(e) => {
+                println!("[Error] Invalid retention policy: {}", e);
+                return;
+            }
+        },
+        None => Box::new(FixedThreshold::default()),
+    };
+
+    let password_policy = match arg_value("--password-classes") {
+        Some(classes) => match classes.parse::<usize>() {
+            Ok(min_classes) if min_classes <= 4 => PasswordPolicy {
+                min_classes,
+                ..PasswordPolicy::default()
+            },
+            _ => {
+                println!("[Error] Invalid number of password classes: {}", classes);
+                return;
+            }
+        },
+        None => PasswordPolicy::default(),
+    };
+
+    let resume_path = arg_value("--resume");
+
+    let mut db = match (arg_value("--wal"), resume_path) {
+        (Some(wal_path), resume_path) => {
+            let snapshot = resume_path.or_else(|| {
+                snapshot_path
+                    .clone()
+                    .filter(|path| Path::new(path).exists())
+            });
+            match EnhancedStudentDatabase::recover(snapshot, wal_path.clone(), retention_policy) {
+                Ok(db) => db,
+                Err(e) => {
+                    println!("[Error] Could not recover from {}: {}", wal_path, e);
+                    return;
+                }
+            }
+        }
+        (None, Some(path)) => match EnhancedStudentDatabase::load_snapshot(&path) {
+            Ok(mut db) => {
+                db.set_retention_policy(retention_policy);
+                db
+            }
+            Err(e) => {
+                println!("[Error] Could not resume from {}: {}", path, e);
+                return;
+            }
+        },
+        (None, None) => {
+            let mut db = EnhancedStudentDatabase::new();
+            db.set_retention_policy(retention_policy);
+            db
+        }
+    };
+
+    db.set_password_policy(password_policy);
 
     // Initialize with static data
     let days_data = generated_data::get_days_data();
     // Process each day's activities
     for day_data in days_data.iter() {
-        let mut local_session_tokens: Vec<String> = Vec::new();
+        let mut local_session_tokens: Vec<SessionToken> = Vec::new();
 
-        println!(
-            "============================[Info] Processing day {}===========================",
-            day_data.day
-        );
+        if day_data.day <= db.current_day() {
+            continue;
+        }
+
+        log::info!("Processing day {}", day_data.day);
 
         if let Some(signups) = &day_data.signups {
-            println!("=========[Info] Processing Signups============");
+            log::debug!("Processing signups");
             for signup in signups {
                 let username = signup.username.clone();
                 let email = signup
                     .email
                     .clone()
                     .unwrap_or_else(|| "no-email@default.com".to_string());
-                let password = signup.password.clone();
+                let password = Password::from(signup.password.clone());
 
                 /*
                 match db.enqueue_user(
@@ -428,9 +1148,9 @@ fn main() {
                 */
 
                 match db.enqueue_user(username, email, password) {
-                    Ok(_) => println!("[Signup] Queued user: {}", signup.username),
-                    Err(e) => println!(
-                        "[Signup Error] Failed to queue user {}: {}",
+                    Ok(_) => log::info!("Queued user: {}", signup.username),
+                    Err(e) => log::warn!(
+                        "Failed to queue user {}: {}",
                         signup.username, e
                     ),
                 }
@@ -438,33 +1158,35 @@ fn main() {
         }
 
         if let Some(logins) = &day_data.logins {
-            println!("=========[Info] Processing Logins============");
+            log::debug!("Processing logins");
             for login in logins {
-                let password = login
-                    .password
-                    .clone()
-                    .chars()
-                    .take(MAX_PASSWORD_LENGTH - 1)
-                    .collect::<String>();
-
                 // Attempt user login
-                println!("[RUST] ATTEMPTING USER LOGIN!\n");
-                match db.login_user(&login.username, &password) {
+                log::trace!("ATTEMPTING USER LOGIN!");
+                match db.login_user(&login.username, &Password::from(login.password.as_str())) {
                     Ok(session_token) => {
-                        println!("[Login] User {} logged in successfully", login.username);
+                        log::info!("User {} logged in successfully", login.username);
                         local_session_tokens.push(session_token);
                     }
                     Err(e) => {
-                        println!(
-                            "[Login Error] Failed to login user {}: {}",
+                        log::warn!(
+                            "Failed to login user {}: {}",
                             login.username, e
                         );
                     }
                 }
             }
         }
-        println!("========[Info] Performing end-of-day updates========");
-        db.increase_day();
+        log::debug!("Performing end-of-day updates");
+        if let Err(e) = db.increase_day() {
+            println!("[Error] Could not end day {}: {}", day_data.day, e);
+            return;
+        }
+
+        if let Some(path) = &snapshot_path {
+            if let Err(e) = db.save_snapshot(path) {
+                log::error!("Failed to save snapshot: {}", e);
+            }
+        }
 
         println!(
             "=====[Info Day {}] Total Site traffic on Rust DB = {}======",
@@ -475,7 +1197,33 @@ fn main() {
 
     println!("\n====================Congratulations! End of Simulation====================\n");
 
-    db.print_both_databases();
+    if let Some(path) = arg_value("--export-json") {
+        if let Err(e) = db.export_json(&path) {
+            log::error!("Failed to export {}: {}", path, e);
+        }
+    }
+    if let Some(path) = arg_value("--export-csv") {
+        if let Err(e) = db.export_csv(&path) {
+            log::error!("Failed to export {}: {}", path, e);
+        }
+    }
+
+    // the final state of both databases is part of the log output, not the summary
+    if log::log_enabled!(log::Level::Info) {
+        db.print_both_databases();
+        log::info!(
+            "{} users, {} inactive for 3 days or more, {} shared between the backends",
+            db.query().count(),
+            db.query().inactivity_at_least(3).count(),
+            db.query().shared().count()
+        );
+    }
+
+    if alloc_registry::is_enabled() {
+        // rust lets go of its users first, so what is left is held by the C database or leaked
+        drop(db);
+        alloc_registry::print_report();
+    }
 
     println!("\n==========================Did you really fix it ?======================================\n");
 }
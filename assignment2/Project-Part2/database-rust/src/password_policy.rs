@@ -0,0 +1,196 @@
+/* ------password_policy.rs------
+ *
+ * rules a password has to pass before it is hashed, for signups and password changes
+ *
+ * checks run in a fixed order so the first failing rule is the reason reported:
+ *   empty -> length -> control characters -> character classes
+ *
+ * nothing is ever truncated, a password over the limit is rejected. MAX_PASSWORD_LENGTH is the
+ * same limit as in database_enhanced.c, and check_length() is also applied to every password
+ * C hands to db_hash_password / db_verify_password, so both backends accept the same passwords
+ *
+ * lengths are in bytes since that is what gets hashed
+ */
+
+// same as MAX_PASSWORD_LENGTH in database_enhanced.c, one byte is the NUL terminator
+pub const MAX_PASSWORD_LENGTH: usize = 100;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PasswordError {
+    Empty,
+    TooShort { len: usize, min: usize },
+    TooLong { len: usize, max: usize },
+    // includes NUL, which C would silently cut the password at
+    ControlCharacter,
+    MissingCharacterClasses { found: usize, required: usize },
+}
+
+impl std::fmt::Display for PasswordError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PasswordError::Empty => write!(f, "password is empty"),
+            PasswordError::TooShort { len, min } => {
+                write!(f, "password is {} bytes long (min {})", len, min)
+            }
+            PasswordError::TooLong { len, max } => {
+                write!(f, "password is {} bytes long (max {})", len, max)
+            }
+            PasswordError::ControlCharacter => write!(f, "password contains a control character"),
+            PasswordError::MissingCharacterClasses { found, required } => write!(
+                f,
+                "password uses {} of lowercase, uppercase, digits and symbols (needs {})",
+                found, required
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PasswordError {}
+
+#[derive(Debug, Clone)]
+pub struct PasswordPolicy {
+    pub min_len: usize,
+    // capped at MAX_PASSWORD_LENGTH - 1 whatever is set here
+    pub max_len: usize,
+    // how many of lowercase, uppercase, digits and symbols have to appear
+    pub min_classes: usize,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_len: 8,
+            max_len: MAX_PASSWORD_LENGTH - 1,
+            min_classes: 2,
+        }
+    }
+}
+
+/// The limit shared by both backends, independent of any configured policy
+pub fn check_length(password: &str) -> Result<(), PasswordError> {
+    if password.is_empty() {
+        return Err(PasswordError::Empty);
+    }
+    if password.len() > MAX_PASSWORD_LENGTH - 1 {
+        return Err(PasswordError::TooLong {
+            len: password.len(),
+            max: MAX_PASSWORD_LENGTH - 1,
+        });
+    }
+    Ok(())
+}
+
+// symbols covers everything that is not an ASCII letter or digit, including non-ASCII letters
+fn character_classes(password: &str) -> usize {
+    let has = |f: fn(&char) -> bool| password.chars().any(|c| f(&c)) as usize;
+    has(char::is_ascii_lowercase)
+        + has(char::is_ascii_uppercase)
+        + has(char::is_ascii_digit)
+        + has(|c| !c.is_ascii_alphanumeric())
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, password: &str) -> Result<(), PasswordError> {
+        check_length(password)?;
+        let len = password.len();
+        if len < self.min_len {
+            return Err(PasswordError::TooShort {
+                len,
+                min: self.min_len,
+            });
+        }
+        if len > self.max_len {
+            return Err(PasswordError::TooLong {
+                len,
+                max: self.max_len,
+            });
+        }
+        if password.chars().any(|c| c.is_control()) {
+            return Err(PasswordError::ControlCharacter);
+        }
+        let found = character_classes(password);
+        if found < self.min_classes {
+            return Err(PasswordError::MissingCharacterClasses {
+                found,
+                required: self.min_classes,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn nothing_is_truncated_at_the_shared_limit() {
+        let policy = PasswordPolicy::default();
+        let longest = format!("A{}", "a".repeat(98));
+        assert_eq!(policy.check(&longest), Ok(()));
+        assert_eq!(
+            policy.check(&format!("{}b", longest)),
+            Err(PasswordError::TooLong { len: 100, max: 99 })
+        );
+        // a policy cannot raise the limit C shares with Rust
+        let lax = PasswordPolicy {
+            min_len: 0,
+            max_len: 1000,
+            min_classes: 0,
+        };
+        assert_eq!(
+            lax.check(&"a".repeat(100)),
+            Err(PasswordError::TooLong { len: 100, max: 99 })
+        );
+        assert_eq!(lax.check(""), Err(PasswordError::Empty));
+    }
+
+    #[test]
+    fn length_is_counted_in_bytes() {
+        let policy = PasswordPolicy::default();
+        // five characters, nine bytes
+        assert_eq!(policy.check("äöüß1"), Ok(()));
+        assert_eq!(
+            policy.check("äöü"),
+            Err(PasswordError::TooShort { len: 6, min: 8 })
+        );
+    }
+
+    #[test]
+    fn nul_is_rejected_before_c_can_cut_at_it() {
+        let policy = PasswordPolicy::default();
+        assert_eq!(
+            policy.check("Passw\0rd1"),
+            Err(PasswordError::ControlCharacter)
+        );
+        assert_eq!(
+            policy.check("Password1\n"),
+            Err(PasswordError::ControlCharacter)
+        );
+    }
+
+    #[test]
+    fn character_classes() {
+        let strict = PasswordPolicy {
+            min_classes: 4,
+            ..PasswordPolicy::default()
+        };
+        assert_eq!(strict.check("Tr0ub4dor&3"), Ok(()));
+        assert_eq!(
+            strict.check("Tr0ub4dor3"),
+            Err(PasswordError::MissingCharacterClasses {
+                found: 3,
+                required: 4
+            })
+        );
+        // non-ASCII letters and spaces count as symbols
+        assert_eq!(PasswordPolicy::default().check("passwörd"), Ok(()));
+        assert_eq!(
+            PasswordPolicy::default().check("12345678"),
+            Err(PasswordError::MissingCharacterClasses {
+                found: 1,
+                required: 2
+            })
+        );
+    }
+}
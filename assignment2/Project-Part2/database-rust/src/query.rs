@@ -0,0 +1,360 @@
+/* ------query.rs------
+ *
+ * read-only queries over the users of both backends, built with EnhancedStudentDatabase::query():
+ *
+ *   db.query().inactivity_at_least(3).run()
+ *   db.query().active(false).email_domain("nus.edu.sg").run()
+ *   db.query().shared().count()
+ *
+ * every predicate added has to hold (they are and-ed), filter() takes any other condition
+ *
+ * a query walks the rust array and then the C database. a user both backends point at (shared
+ * during a join) is returned once, with both in_rust() and in_c() set. results are ordered by
+ * user_id
+ *
+ * a UserView borrows rust users and holds a copy of C users, it has no setters and never hands
+ * out a pointer. the session token and password hash are only visible as has_session() and
+ * password_hash_version()
+ */
+
+use crate::database_fix_full::{normalize_username, OwnershipType, UserDatabase, UserStruct};
+use crate::database_wrapper::DatabaseExtensions;
+use std::borrow::Cow;
+use std::collections::HashSet;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    Rust,
+    C,
+}
+
+#[derive(Debug, Clone)]
+pub struct UserView<'a> {
+    user: Cow<'a, UserStruct>,
+    in_rust: bool,
+    in_c: bool,
+}
+
+impl<'a> UserView<'a> {
+    pub fn username(&self) -> &str {
+        self.user.username.as_str()
+    }
+
+    pub fn user_id(&self) -> i32 {
+        self.user.user_id
+    }
+
+    pub fn email(&self) -> &str {
+        self.user.email.as_str()
+    }
+
+    // the part after the last '@', empty when there is none
+    pub fn email_domain(&self) -> &str {
+        self.email().rsplit_once('@').map_or("", |(_, domain)| domain)
+    }
+
+    pub fn inactivity_count(&self) -> i32 {
+        self.user.inactivity_count
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.user.is_active != 0
+    }
+
+    pub fn has_session(&self) -> bool {
+        !self.user.session_token.expose().is_empty()
+    }
+
+    pub fn ownership(&self) -> OwnershipType {
+        self.user.ownership()
+    }
+
+    // 0 for a user without a usable password (see PasswordHash::is_valid)
+    pub fn password_hash_version(&self) -> u32 {
+        self.user.password_hash.version
+    }
+
+    pub fn in_rust(&self) -> bool {
+        self.in_rust
+    }
+
+    pub fn in_c(&self) -> bool {
+        self.in_c
+    }
+
+    pub fn in_backend(&self, backend: Backend) -> bool {
+        match backend {
+            Backend::Rust => self.in_rust,
+            Backend::C => self.in_c,
+        }
+    }
+}
+
+// a view of a copy of user, for tests that do not need a database behind it
+#[cfg(test)]
+impl UserView<'static> {
+    pub fn detached(user: UserStruct, in_rust: bool, in_c: bool) -> Self {
+        UserView {
+            user: Cow::Owned(user),
+            in_rust,
+            in_c,
+        }
+    }
+}
+
+type Predicate<'q> = Box<dyn Fn(&UserView) -> bool + 'q>;
+
+pub struct Query<'a> {
+    rust_db: &'a UserDatabase,
+    c_db: &'a DatabaseExtensions,
+    predicates: Vec<Predicate<'a>>,
+    limit: Option<usize>,
+}
+
+impl<'a> Query<'a> {
+    pub fn new(rust_db: &'a UserDatabase, c_db: &'a DatabaseExtensions) -> Self {
+        Query {
+            rust_db,
+            c_db,
+            predicates: Vec::new(),
+            limit: None,
+        }
+    }
+
+    pub fn filter<F: Fn(&UserView) -> bool + 'a>(mut self, predicate: F) -> Self {
+        self.predicates.push(Box::new(predicate));
+        self
+    }
+
+    // same matching rules as username lookups (normalize_username)
+    pub fn username(self, username: &str) -> Self {
+        let username = normalize_username(username);
+        self.filter(move |u| normalize_username(u.username()) == username)
+    }
+
+    pub fn username_contains(self, part: &str) -> Self {
+        let part = normalize_username(part);
+        self.filter(move |u| normalize_username(u.username()).contains(&part))
+    }
+
+    pub fn user_id(self, user_id: i32) -> Self {
+        self.filter(move |u| u.user_id() == user_id)
+    }
+
+    // inclusive on both ends
+    pub fn user_id_between(self, low: i32, high: i32) -> Self {
+        self.filter(move |u| (low..=high).contains(&u.user_id()))
+    }
+
+    pub fn email(self, email: &str) -> Self {
+        let email = email.to_string();
+        self.filter(move |u| u.email().eq_ignore_ascii_case(&email))
+    }
+
+    pub fn email_domain(self, domain: &str) -> Self {
+        let domain = domain.trim_start_matches('@').to_string();
+        self.filter(move |u| u.email_domain().eq_ignore_ascii_case(&domain))
+    }
+
+    pub fn inactivity_at_least(self, days: i32) -> Self {
+        self.filter(move |u| u.inactivity_count() >= days)
+    }
+
+    pub fn inactivity_at_most(self, days: i32) -> Self {
+        self.filter(move |u| u.inactivity_count() <= days)
+    }
+
+    pub fn active(self, active: bool) -> Self {
+        self.filter(move |u| u.is_active() == active)
+    }
+
+    pub fn has_session(self, has_session: bool) -> Self {
+        self.filter(move |u| u.has_session() == has_session)
+    }
+
+    pub fn ownership(self, ownership: OwnershipType) -> Self {
+        self.filter(move |u| u.ownership() == ownership)
+    }
+
+    // SHARED_RUST_PRIMARY or SHARED_C_PRIMARY
+    pub fn shared(self) -> Self {
+        self.filter(|u| {
+            matches!(
+                u.ownership(),
+                OwnershipType::SHARED_RUST_PRIMARY | OwnershipType::SHARED_C_PRIMARY
+            )
+        })
+    }
+
+    pub fn password_hash_version(self, version: u32) -> Self {
+        self.filter(move |u| u.password_hash_version() == version)
+    }
+
+    pub fn backend(self, backend: Backend) -> Self {
+        self.filter(move |u| u.in_backend(backend))
+    }
+
+    pub fn limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    // every user of both backends once, before any predicate
+    fn views(&self) -> Vec<UserView<'a>> {
+        let c_pointers = self.c_db.c_user_pointers();
+        let in_c: HashSet<usize> = c_pointers.iter().map(|ptr| *ptr as usize).collect();
+        let mut seen: HashSet<usize> = HashSet::new();
+        let mut views = Vec::new();
+
+        for user in self.rust_db {
+            let addr = user as *const UserStruct as usize;
+            seen.insert(addr);
+            views.push(UserView {
+                user: Cow::Borrowed(user),
+                in_rust: true,
+                in_c: in_c.contains(&addr),
+            });
+        }
+        for ptr in c_pointers {
+            if !seen.insert(ptr as usize) {
+                continue;
+            }
+            views.push(UserView {
+                user: Cow::Owned(self.c_db.read_user(ptr)),
+                in_rust: false,
+                in_c: true,
+            });
+        }
+        views
+    }
+
+    pub fn run(&self) -> Vec<UserView<'a>> {
+        let mut matches: Vec<UserView<'a>> = self
+            .views()
+            .into_iter()
+            .filter(|u| self.predicates.iter().all(|p| p(u)))
+            .collect();
+        matches.sort_by_key(|u| u.user_id());
+        if let Some(limit) = self.limit {
+            matches.truncate(limit);
+        }
+        matches
+    }
+
+    pub fn count(&self) -> usize {
+        self.run().len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_fix_full::{
+        add_user, create_user_with_hash, find_user_by_username, find_user_by_username_mut,
+        init_database, PasswordHash, Role,
+    };
+    use crate::database_wrapper::{initialize_enhanced_database, UserStructT, C_BACKEND_LOCK};
+
+    fn hash() -> PasswordHash {
+        PasswordHash {
+            version: 1,
+            iterations: 1000,
+            salt: [1; 16],
+            hash: [2; 32],
+        }
+    }
+
+    // alice and bob only in rust (bob shared with C), carol only in C
+    fn databases() -> (Box<UserDatabase>, DatabaseExtensions) {
+        // C keeps pointers to the day counter and the id allocator
+        let day: &'static i32 = Box::leak(Box::new(1));
+        let mut rust_db = init_database();
+        let c_db = initialize_enhanced_database(day, &mut rust_db.id_allocator);
+        for (username, email, inactive_days) in [
+            ("alice", "alice@example.com", 0),
+            ("Bob", "bob@nus.edu.sg", 4),
+        ] {
+            add_user(
+                &mut rust_db,
+                create_user_with_hash(username, email, 0, hash()),
+            )
+            .unwrap();
+            find_user_by_username_mut(&mut rust_db, username)
+                .unwrap()
+                .inactivity_count = inactive_days;
+        }
+        let bob = find_user_by_username(&rust_db, "bob").unwrap() as *const UserStruct;
+        c_db.sync_user_from_rust_db(bob as *mut UserStructT);
+        c_db.sync_user_to_c_backend("carol", "carol@NUS.edu.sg", 0, &hash(), Role::Student)
+            .unwrap();
+        (rust_db, c_db)
+    }
+
+    fn usernames(views: &[UserView]) -> Vec<String> {
+        views.iter().map(|u| u.username().to_string()).collect()
+    }
+
+    #[test]
+    fn every_user_once_in_user_id_order() {
+        let _lock = C_BACKEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (rust_db, c_db) = databases();
+        let all = Query::new(&rust_db, &c_db).run();
+        assert_eq!(usernames(&all), ["alice", "Bob", "carol"]);
+        assert!(all.windows(2).all(|w| w[0].user_id() < w[1].user_id()));
+        let backends: Vec<(bool, bool)> = all.iter().map(|u| (u.in_rust(), u.in_c())).collect();
+        assert_eq!(backends, [(true, false), (true, true), (false, true)]);
+        assert_eq!(all[1].ownership(), OwnershipType::SHARED_C_PRIMARY);
+        assert!(!all[0].has_session());
+    }
+
+    #[test]
+    fn predicates_are_combined() {
+        let _lock = C_BACKEND_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let (rust_db, c_db) = databases();
+        let query = || Query::new(&rust_db, &c_db);
+        for (name, views, expected) in [
+            ("username", query().username("BOB").run(), vec!["Bob"]),
+            (
+                "username_contains",
+                query().username_contains("L").run(),
+                vec!["alice", "carol"],
+            ),
+            (
+                "email",
+                query().email("ALICE@example.com").run(),
+                vec!["alice"],
+            ),
+            (
+                "email_domain",
+                query().email_domain("@nus.edu.sg").run(),
+                vec!["Bob", "carol"],
+            ),
+            (
+                "inactivity",
+                query().inactivity_at_least(1).run(),
+                vec!["Bob"],
+            ),
+            (
+                "backend",
+                query().backend(Backend::C).run(),
+                vec!["Bob", "carol"],
+            ),
+            ("shared", query().shared().run(), vec!["Bob"]),
+            (
+                "and",
+                query().backend(Backend::C).inactivity_at_most(0).run(),
+                vec!["carol"],
+            ),
+            (
+                "filter",
+                query().filter(|u| u.email().starts_with('a')).run(),
+                vec!["alice"],
+            ),
+            ("limit", query().limit(2).run(), vec!["alice", "Bob"]),
+            ("none", query().active(false).run(), vec![]),
+        ] {
+            assert_eq!(usernames(&views), expected, "{}", name);
+        }
+        assert_eq!(query().user_id_between(2, 3).count(), 2);
+    }
+}
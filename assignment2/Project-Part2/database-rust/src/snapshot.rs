@@ -0,0 +1,568 @@
+/* ------snapshot.rs------
+ *
+ * binary snapshot of both databases so a simulation can be stopped after any day and resumed
+ *
+ * layout (all integers little endian):
+ *   header:  magic "SDBSNAP\0" | version u32 | day i32 | next_user_id i32
+ *            | c_allocated_users count u32 | c_allocated_users i32 * count | record count u32
+ *            | archived count u32 | crc32 u32 (over the header)
+ *   record:  backends u8 | user | crc32 u32 (over everything before it in the record)
+ *   archived: removed_on_day i32 | reason u8 | days i32 | user | crc32 u32 (same)
+ *   user:    ownership u8 | role u8 | user_id i32 | inactivity_count i32 | is_active i32
+ *            | username [u8; 50] | email [u8; 50] | session_token [u8; 32]
+ *            | hash version u32 | hash iterations u32 | salt [u8; 16] | hash [u8; 32]
+ *
+ * the records come first, then the archived users. version 1 had no archive (and no archived
+ * count in the header), it is still read with an empty archive. versions 1 and 2 had no role,
+ * their users are read back as students
+ *
+ * every allocation is written once, backends says which databases held a pointer to it
+ * and ownership says which side allocated it, so shared users are restored as one allocation
+ * sessions are not saved - they belong to the C session manager and expire after a day anyway.
+ * the session_token field is always written as zeros and ignored when read (older files may have
+ * a token there), so no live credential is ever stored at rest
+ */
+
+use crate::database_fix_full::{
+    ArchivedUser, FixedStr, OwnershipType, PasswordHash, RemovalReason, Role, Tombstone,
+    UserRecord, UserStruct,
+};
+use std::borrow::Cow;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+pub const SNAPSHOT_MAGIC: &[u8; 8] = b"SDBSNAP\0";
+pub const SNAPSHOT_VERSION: u32 = 3;
+// same as MAX_SESSION_TOKEN_LEN in database_fix_full.rs
+const SESSION_TOKEN_LEN: usize = 32;
+
+// which databases a record was in
+pub const IN_RUST_DB: u8 = 1;
+pub const IN_C_DB: u8 = 2;
+
+#[derive(Debug)]
+pub enum SnapshotError {
+    Io(std::io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    Truncated,
+    HeaderChecksumMismatch,
+    ChecksumMismatch { record: usize },
+    BadOwnershipTag { record: usize, tag: u8 },
+    BadRoleTag { record: usize, tag: u8 },
+    BadReasonTag { record: usize, tag: u8 },
+    Restore(String),
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SnapshotError::Io(e) => write!(f, "snapshot io error: {}", e),
+            SnapshotError::BadMagic => write!(f, "not a database snapshot (bad magic)"),
+            SnapshotError::UnsupportedVersion(v) => {
+                write!(f, "unsupported snapshot version {} (expected {})", v, SNAPSHOT_VERSION)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::HeaderChecksumMismatch => write!(f, "checksum mismatch in header"),
+            SnapshotError::ChecksumMismatch { record } => {
+                write!(f, "checksum mismatch in record {}", record)
+            }
+            SnapshotError::BadOwnershipTag { record, tag } => {
+                write!(f, "unknown ownership tag {} in record {}", tag, record)
+            }
+            SnapshotError::BadRoleTag { record, tag } => {
+                write!(f, "unknown role {} in record {}", tag, record)
+            }
+            SnapshotError::BadReasonTag { record, tag } => {
+                write!(f, "unknown removal reason {} in archived record {}", tag, record)
+            }
+            SnapshotError::Restore(e) => write!(f, "failed to restore snapshot: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+impl From<std::io::Error> for SnapshotError {
+    fn from(e: std::io::Error) -> Self {
+        SnapshotError::Io(e)
+    }
+}
+
+pub struct SnapshotRecord {
+    pub backends: u8,
+    pub user: UserStruct,
+}
+
+// the archive is borrowed when saving, every archived user is a registered allocation and copying
+// them just to write them out would allocate and register each one again
+pub struct Snapshot<'a> {
+    pub day: i32,
+    pub next_user_id: i32,
+    pub c_allocated_users: Vec<i32>,
+    pub records: Vec<SnapshotRecord>,
+    pub archive: Cow<'a, [ArchivedUser]>,
+}
+
+pub fn ownership_tag(ownership: &OwnershipType) -> u8 {
+    match ownership {
+        OwnershipType::RUST_OWNED => 0,
+        OwnershipType::C_OWNED => 1,
+        OwnershipType::SHARED_RUST_PRIMARY => 2,
+        OwnershipType::SHARED_C_PRIMARY => 3,
+    }
+}
+
+pub fn ownership_from_tag(tag: u8) -> Option<OwnershipType> {
+    match tag {
+        0 => Some(OwnershipType::RUST_OWNED),
+        1 => Some(OwnershipType::C_OWNED),
+        2 => Some(OwnershipType::SHARED_RUST_PRIMARY),
+        3 => Some(OwnershipType::SHARED_C_PRIMARY),
+        _ => None,
+    }
+}
+
+// plain bitwise CRC-32 (IEEE), records are small so a lookup table isnt worth it
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for &b in bytes {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+fn encode_user(user: &UserStruct, out: &mut Vec<u8>) {
+    out.push(ownership_tag(&user.ownership()));
+    out.push(user.role as u8);
+    out.extend_from_slice(&user.user_id.to_le_bytes());
+    out.extend_from_slice(&user.inactivity_count.to_le_bytes());
+    out.extend_from_slice(&user.is_active.to_le_bytes());
+    out.extend_from_slice(user.username.raw());
+    out.extend_from_slice(user.email.raw());
+    // kept in the layout, never written (see the header comment)
+    out.extend_from_slice(&[0u8; SESSION_TOKEN_LEN]);
+    out.extend_from_slice(&user.password_hash.version.to_le_bytes());
+    out.extend_from_slice(&user.password_hash.iterations.to_le_bytes());
+    out.extend_from_slice(&user.password_hash.salt);
+    out.extend_from_slice(&user.password_hash.hash);
+}
+
+fn encode_record(record: &SnapshotRecord, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.push(record.backends);
+    encode_user(&record.user, out);
+    let checksum = crc32(&out[start..]);
+    out.extend_from_slice(&checksum.to_le_bytes());
+}
+
+fn encode_archived(archived: &ArchivedUser, out: &mut Vec<u8>) {
+    let start = out.len();
+    out.extend_from_slice(&archived.tombstone.removed_on_day.to_le_bytes());
+    match archived.tombstone.reason {
+        RemovalReason::Inactivity { days } => {
+            out.push(0);
+            out.extend_from_slice(&days.to_le_bytes());
+        }
+    }
+    encode_user(&archived.user, out);
+    let checksum = crc32(&out[start..]);
+    out.extend_from_slice(&checksum.to_le_bytes());
+}
+
+pub fn encode(snapshot: &Snapshot) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(SNAPSHOT_MAGIC);
+    out.extend_from_slice(&SNAPSHOT_VERSION.to_le_bytes());
+    out.extend_from_slice(&snapshot.day.to_le_bytes());
+    out.extend_from_slice(&snapshot.next_user_id.to_le_bytes());
+    out.extend_from_slice(&(snapshot.c_allocated_users.len() as u32).to_le_bytes());
+    for id in &snapshot.c_allocated_users {
+        out.extend_from_slice(&id.to_le_bytes());
+    }
+    out.extend_from_slice(&(snapshot.records.len() as u32).to_le_bytes());
+    out.extend_from_slice(&(snapshot.archive.len() as u32).to_le_bytes());
+    let checksum = crc32(&out);
+    out.extend_from_slice(&checksum.to_le_bytes());
+    for record in &snapshot.records {
+        encode_record(record, &mut out);
+    }
+    for archived in snapshot.archive.iter() {
+        encode_archived(archived, &mut out);
+    }
+    out
+}
+
+// reads fixed size pieces off the front of the buffer
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    // of the file being read, the user layout depends on it
+    version: u32,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], SnapshotError> {
+        if self.bytes.len() - self.pos < n {
+            return Err(SnapshotError::Truncated);
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SnapshotError> {
+        let mut arr = [0u8; N];
+        arr.copy_from_slice(self.take(N)?);
+        Ok(arr)
+    }
+
+    fn u8(&mut self) -> Result<u8, SnapshotError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, SnapshotError> {
+        Ok(u32::from_le_bytes(self.array::<4>()?))
+    }
+
+    fn i32(&mut self) -> Result<i32, SnapshotError> {
+        Ok(i32::from_le_bytes(self.array::<4>()?))
+    }
+}
+
+// the ownership and role tags are checked by the caller (with_tags), after the checksum
+fn decode_user(reader: &mut Reader) -> Result<(u8, u8, UserStruct), SnapshotError> {
+    let tag = reader.u8()?;
+    let role_tag = if reader.version >= 3 { reader.u8()? } else { 0 };
+    let user_id = reader.i32()?;
+    let inactivity_count = reader.i32()?;
+    let is_active = reader.i32()?;
+    let username = FixedStr::from_raw(reader.array()?);
+    let email = FixedStr::from_raw(reader.array()?);
+    let _session_token: [u8; SESSION_TOKEN_LEN] = reader.array()?;
+    let password_hash = PasswordHash {
+        version: reader.u32()?,
+        iterations: reader.u32()?,
+        salt: reader.array()?,
+        hash: reader.array()?,
+    };
+    // ownership comes from the tag (with_ownership), ref_count and allocator are set when the
+    // record is allocated again, by whichever side restores it
+    let mut user = UserStruct::default();
+    user.password_hash = password_hash;
+    user.username = username;
+    user.user_id = user_id;
+    user.email = email;
+    user.inactivity_count = inactivity_count;
+    user.is_active = is_active;
+    Ok((tag, role_tag, user))
+}
+
+fn with_tags(user: UserStruct, tag: u8, role_tag: u8, record: usize) -> Result<UserStruct, SnapshotError> {
+    let ownership = ownership_from_tag(tag).ok_or(SnapshotError::BadOwnershipTag { record, tag })?;
+    let mut user = user.with_ownership(ownership);
+    user.role = Role::from_i32(role_tag as i32).ok_or(SnapshotError::BadRoleTag {
+        record,
+        tag: role_tag,
+    })?;
+    Ok(user)
+}
+
+fn decode_record(reader: &mut Reader, index: usize) -> Result<SnapshotRecord, SnapshotError> {
+    let start = reader.pos;
+    let backends = reader.u8()?;
+    let (tag, role_tag, user) = decode_user(reader)?;
+    let computed = crc32(&reader.bytes[start..reader.pos]);
+    if reader.u32()? != computed {
+        return Err(SnapshotError::ChecksumMismatch { record: index });
+    }
+    let user = with_tags(user, tag, role_tag, index)?;
+    Ok(SnapshotRecord { backends, user })
+}
+
+// archived records are numbered after the live ones in errors
+fn decode_archived(reader: &mut Reader, index: usize) -> Result<ArchivedUser, SnapshotError> {
+    let start = reader.pos;
+    let removed_on_day = reader.i32()?;
+    let reason_tag = reader.u8()?;
+    let days = reader.i32()?;
+    let (tag, role_tag, user) = decode_user(reader)?;
+    let computed = crc32(&reader.bytes[start..reader.pos]);
+    if reader.u32()? != computed {
+        return Err(SnapshotError::ChecksumMismatch { record: index });
+    }
+    let user = with_tags(user, tag, role_tag, index)?;
+    let reason = match reason_tag {
+        0 => RemovalReason::Inactivity { days },
+        tag => return Err(SnapshotError::BadReasonTag { record: index, tag }),
+    };
+    Ok(ArchivedUser {
+        user: UserRecord::new(user),
+        tombstone: Tombstone {
+            removed_on_day,
+            reason,
+        },
+    })
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Snapshot<'static>, SnapshotError> {
+    let mut reader = Reader {
+        bytes,
+        pos: 0,
+        version: 0,
+    };
+    if reader.take(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+    let version = reader.u32()?;
+    if version == 0 || version > SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+    reader.version = version;
+    let day = reader.i32()?;
+    let next_user_id = reader.i32()?;
+    let c_allocated_count = reader.u32()?;
+    let mut c_allocated_users = Vec::new();
+    for _ in 0..c_allocated_count {
+        c_allocated_users.push(reader.i32()?);
+    }
+    let record_count = reader.u32()?;
+    let archived_count = if version >= 2 { reader.u32()? } else { 0 };
+    let computed = crc32(&reader.bytes[..reader.pos]);
+    if reader.u32()? != computed {
+        return Err(SnapshotError::HeaderChecksumMismatch);
+    }
+    let mut records = Vec::new();
+    for i in 0..record_count as usize {
+        records.push(decode_record(&mut reader, i)?);
+    }
+    let mut archive = Vec::new();
+    for i in 0..archived_count as usize {
+        archive.push(decode_archived(&mut reader, record_count as usize + i)?);
+    }
+    Ok(Snapshot {
+        day,
+        next_user_id,
+        c_allocated_users,
+        records,
+        archive: Cow::Owned(archive),
+    })
+}
+
+// written to a temp file first and renamed, so a crash mid-write never leaves a half snapshot behind.
+// the temp file is synced before the rename and the directory after it, otherwise a crash can
+// still leave the new name pointing at data that never reached the disk
+pub fn write_file(path: &Path, snapshot: &Snapshot) -> Result<(), SnapshotError> {
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    file.write_all(&encode(snapshot))?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    fs::File::open(dir)?.sync_all()?;
+    Ok(())
+}
+
+pub fn read_file(path: &Path) -> Result<Snapshot<'static>, SnapshotError> {
+    decode(&fs::read(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database_fix_full::SessionToken;
+
+    fn user(username: &str, user_id: i32, ownership: OwnershipType) -> UserStruct {
+        let mut user = UserStruct::default();
+        user.username = FixedStr::truncated(username);
+        user.email = FixedStr::truncated(&format!("{}@example.com", username));
+        user.user_id = user_id;
+        user.inactivity_count = user_id * 2;
+        user.is_active = 1;
+        user.password_hash = PasswordHash {
+            version: 1,
+            iterations: 1000,
+            salt: [user_id as u8; 16],
+            hash: [0xAB; 32],
+        };
+        user.with_ownership(ownership)
+    }
+
+    fn sample() -> Snapshot<'static> {
+        let mut with_session = user("carol", 3, OwnershipType::SHARED_C_PRIMARY);
+        with_session.role = Role::Staff;
+        let mut token = [0u8; SESSION_TOKEN_LEN];
+        token[..14].copy_from_slice(b"secret-session");
+        with_session.session_token = SessionToken::from_raw(token);
+        Snapshot {
+            day: 7,
+            next_user_id: 5,
+            c_allocated_users: vec![2],
+            records: vec![
+                SnapshotRecord {
+                    backends: IN_RUST_DB,
+                    user: user("alice", 1, OwnershipType::RUST_OWNED),
+                },
+                SnapshotRecord {
+                    backends: IN_C_DB,
+                    user: user("bob", 2, OwnershipType::C_OWNED),
+                },
+                SnapshotRecord {
+                    backends: IN_RUST_DB | IN_C_DB,
+                    user: with_session,
+                },
+            ],
+            archive: Cow::Owned(vec![ArchivedUser {
+                user: UserRecord::new(user("dave", 4, OwnershipType::RUST_OWNED)),
+                tombstone: Tombstone {
+                    removed_on_day: 6,
+                    reason: RemovalReason::Inactivity { days: 6 },
+                },
+            }]),
+        }
+    }
+
+    fn assert_same_user(a: &UserStruct, b: &UserStruct) {
+        assert_eq!(a.username.as_str(), b.username.as_str());
+        assert_eq!(a.email.as_str(), b.email.as_str());
+        assert_eq!(a.user_id, b.user_id);
+        assert_eq!(a.inactivity_count, b.inactivity_count);
+        assert_eq!(a.is_active, b.is_active);
+        assert_eq!(a.ownership(), b.ownership());
+        assert_eq!(a.role, b.role);
+        assert!(
+            a.password_hash == b.password_hash,
+            "password hash of {:?}",
+            a.username
+        );
+    }
+
+    #[test]
+    fn round_trip() {
+        let snapshot = sample();
+        let decoded = decode(&encode(&snapshot)).unwrap();
+        assert_eq!(decoded.day, 7);
+        assert_eq!(decoded.next_user_id, 5);
+        assert_eq!(decoded.c_allocated_users, vec![2]);
+        assert_eq!(decoded.records.len(), 3);
+        for (a, b) in snapshot.records.iter().zip(decoded.records.iter()) {
+            assert_eq!(a.backends, b.backends);
+            assert_same_user(&a.user, &b.user);
+        }
+        assert_eq!(decoded.archive.len(), 1);
+        assert_same_user(&snapshot.archive[0].user, &decoded.archive[0].user);
+        assert_eq!(decoded.archive[0].tombstone.removed_on_day, 6);
+        assert!(matches!(
+            decoded.archive[0].tombstone.reason,
+            RemovalReason::Inactivity { days: 6 }
+        ));
+    }
+
+    #[test]
+    fn session_token_is_never_saved() {
+        let bytes = encode(&sample());
+        assert!(
+            !bytes.windows(14).any(|w| w == b"secret-session"),
+            "the session token was written to the snapshot"
+        );
+        let decoded = decode(&bytes).unwrap();
+        assert_eq!(decoded.records[2].user.session_token.expose(), "");
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        let bytes = encode(&sample());
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(matches!(decode(&bad_magic), Err(SnapshotError::BadMagic)));
+
+        let mut bad_version = bytes.clone();
+        bad_version[8..12].copy_from_slice(&99u32.to_le_bytes());
+        assert!(matches!(
+            decode(&bad_version),
+            Err(SnapshotError::UnsupportedVersion(99))
+        ));
+
+        // the day, covered by the header checksum
+        let mut bad_day = bytes.clone();
+        bad_day[12] ^= 1;
+        assert!(matches!(
+            decode(&bad_day),
+            Err(SnapshotError::HeaderChecksumMismatch)
+        ));
+    }
+
+    #[test]
+    fn rejects_corrupt_records() {
+        let bytes = encode(&sample());
+        // header: magic, version, day, next_user_id, 1 c_allocated_user, record and archived counts, crc
+        let header_len = 8 + 4 + 4 + 4 + 4 + 4 + 4 + 4 + 4;
+        let record_len = 1 + 1 + 1 + 4 * 3 + 50 + 50 + SESSION_TOKEN_LEN + 4 + 4 + 16 + 32 + 4;
+        for record in 0..3 {
+            let mut corrupt = bytes.clone();
+            // a byte of the username
+            corrupt[header_len + record * record_len + 20] ^= 0x20;
+            match decode(&corrupt) {
+                Err(SnapshotError::ChecksumMismatch { record: r }) => assert_eq!(r, record),
+                other => panic!("record {}: {:?}", record, other.map(|_| ())),
+            }
+        }
+
+        let mut bad_tag = bytes.clone();
+        let tag_pos = header_len + 1;
+        bad_tag[tag_pos] = 9;
+        let start = header_len;
+        let end = header_len + record_len - 4;
+        let checksum = crc32(&bad_tag[start..end]);
+        bad_tag[end..end + 4].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            decode(&bad_tag),
+            Err(SnapshotError::BadOwnershipTag { record: 0, tag: 9 })
+        ));
+
+        let mut bad_role = bytes.clone();
+        bad_role[tag_pos + 1] = 7;
+        let checksum = crc32(&bad_role[start..end]);
+        bad_role[end..end + 4].copy_from_slice(&checksum.to_le_bytes());
+        assert!(matches!(
+            decode(&bad_role),
+            Err(SnapshotError::BadRoleTag { record: 0, tag: 7 })
+        ));
+    }
+
+    #[test]
+    fn rejects_truncated() {
+        let bytes = encode(&sample());
+        for len in 0..bytes.len() {
+            assert!(
+                decode(&bytes[..len]).is_err(),
+                "decoded from {} of {} bytes",
+                len,
+                bytes.len()
+            );
+        }
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("snapshot-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.snap");
+        write_file(&path, &sample()).unwrap();
+        assert!(!path.with_extension("tmp").exists());
+        let decoded = read_file(&path).unwrap();
+        assert_eq!(decoded.records.len(), 3);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(matches!(read_file(&path), Err(SnapshotError::Io(_))));
+    }
+}
@@ -0,0 +1,289 @@
+/* ------username_policy.rs------
+ *
+ * rules a username has to pass before it is queued for signup or used in a rename
+ *
+ * checks run in a fixed order so the first failing rule is the reason reported:
+ *   length -> control characters -> html markup -> path characters -> charset
+ *   -> mixed scripts -> reserved names -> names confusable with a reserved name
+ *
+ * lengths are in bytes since that is what the fixed size username field holds
+ */
+
+// same size as the username field in UserStruct / UserStruct_t, one byte is the NUL terminator
+pub const MAX_NAME_LEN: usize = 50;
+
+// characters that would turn into markup if a username is ever shown in a page
+const HTML_CHARS: &[char] = &['<', '>', '&', '"', '\'', '`'];
+// characters that make a username usable as (part of) a file path
+const PATH_CHARS: &[char] = &['/', '\\'];
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UsernameError {
+    TooShort { len: usize, min: usize },
+    TooLong { len: usize, max: usize },
+    ControlCharacter,
+    HtmlMarkup(char),
+    // a path separator, or ".." anywhere in the name
+    PathCharacters,
+    DisallowedChar(char),
+    // Latin letters mixed with Cyrillic or Greek ones
+    MixedScript,
+    Reserved { name: String },
+    ConfusableWithReserved { reserved: String },
+}
+
+impl std::fmt::Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameError::TooShort { len, min } => {
+                write!(f, "username is {} bytes long (min {})", len, min)
+            }
+            UsernameError::TooLong { len, max } => {
+                write!(f, "username is {} bytes long (max {})", len, max)
+            }
+            UsernameError::ControlCharacter => write!(f, "username contains a control character"),
+            UsernameError::HtmlMarkup(c) => {
+                write!(f, "username contains the markup character {:?}", c)
+            }
+            UsernameError::PathCharacters => {
+                write!(f, "username contains a path separator or \"..\"")
+            }
+            UsernameError::DisallowedChar(c) => {
+                write!(f, "character {:?} is not allowed in usernames", c)
+            }
+            UsernameError::MixedScript => {
+                write!(f, "username mixes Latin letters with Cyrillic or Greek ones")
+            }
+            UsernameError::Reserved { name } => write!(f, "username {:?} is reserved", name),
+            UsernameError::ConfusableWithReserved { reserved } => {
+                write!(f, "username looks like the reserved name {:?}", reserved)
+            }
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+#[derive(Debug, Clone)]
+pub struct UsernamePolicy {
+    pub min_len: usize,
+    pub max_len: usize,
+    // allowed besides ASCII letters and digits
+    pub extra_chars: String,
+    // letters and digits outside ASCII (checked for mixed scripts)
+    pub allow_non_ascii: bool,
+    // compared case-insensitively
+    pub reserved_names: Vec<String>,
+    pub reject_html: bool,
+    pub reject_path_chars: bool,
+    // reject names that only differ from a reserved name by lookalike characters, e.g. "r00t", "Аdmin"
+    pub reject_confusables: bool,
+}
+
+impl Default for UsernamePolicy {
+    fn default() -> Self {
+        UsernamePolicy {
+            min_len: 3,
+            max_len: MAX_NAME_LEN - 1,
+            extra_chars: "_-.".to_string(),
+            allow_non_ascii: false,
+            reserved_names: [
+                "root",
+                "admin",
+                "administrator",
+                "system",
+                "daemon",
+                "nobody",
+                "guest",
+                "null",
+                "superuser",
+            ]
+            .iter()
+            .map(|name| name.to_string())
+            .collect(),
+            reject_html: true,
+            reject_path_chars: true,
+            reject_confusables: true,
+        }
+    }
+}
+
+fn is_cyrillic_or_greek(c: char) -> bool {
+    ('\u{0370}'..='\u{03FF}').contains(&c) || ('\u{0400}'..='\u{04FF}').contains(&c)
+}
+
+// folds lookalike characters together so "R0OT", "r_o_o_t" and "rооt" (Cyrillic o) all become "root"
+fn skeleton(name: &str) -> String {
+    name.chars()
+        .filter(|c| !matches!(c, '_' | '-' | '.'))
+        .map(|c| match c.to_lowercase().next().unwrap_or(c) {
+            '0' | 'о' | 'ο' => 'o',
+            '1' | 'i' | '|' | 'і' | 'ι' => 'l',
+            '3' | 'е' | 'ε' => 'e',
+            '4' | '@' | 'а' | 'α' => 'a',
+            '5' | '$' | 'ѕ' => 's',
+            '7' | 'т' | 'τ' => 't',
+            '8' | 'в' | 'β' => 'b',
+            'р' | 'ρ' => 'p',
+            'с' => 'c',
+            'х' | 'χ' => 'x',
+            'у' => 'y',
+            'м' => 'm',
+            'н' | 'η' => 'h',
+            'к' | 'κ' => 'k',
+            'ν' => 'v',
+            'ω' => 'w',
+            other => other,
+        })
+        .collect()
+}
+
+impl UsernamePolicy {
+    pub fn check(&self, username: &str) -> Result<(), UsernameError> {
+        let len = username.len();
+        if len < self.min_len {
+            return Err(UsernameError::TooShort {
+                len,
+                min: self.min_len,
+            });
+        }
+        if len > self.max_len {
+            return Err(UsernameError::TooLong {
+                len,
+                max: self.max_len,
+            });
+        }
+        if username.chars().any(|c| c.is_control()) {
+            return Err(UsernameError::ControlCharacter);
+        }
+        if self.reject_html {
+            if let Some(c) = username.chars().find(|c| HTML_CHARS.contains(c)) {
+                return Err(UsernameError::HtmlMarkup(c));
+            }
+        }
+        if self.reject_path_chars
+            && (username.contains(PATH_CHARS) || username.contains(".."))
+        {
+            return Err(UsernameError::PathCharacters);
+        }
+        let allowed = |c: char| {
+            c.is_ascii_alphanumeric()
+                || self.extra_chars.contains(c)
+                || (self.allow_non_ascii && !c.is_ascii() && c.is_alphanumeric())
+        };
+        if let Some(c) = username.chars().find(|&c| !allowed(c)) {
+            return Err(UsernameError::DisallowedChar(c));
+        }
+        if username.chars().any(|c| c.is_ascii_alphabetic())
+            && username.chars().any(is_cyrillic_or_greek)
+        {
+            return Err(UsernameError::MixedScript);
+        }
+
+        let lowered = username.to_lowercase();
+        if let Some(name) = self.reserved_names.iter().find(|r| r.to_lowercase() == lowered) {
+            return Err(UsernameError::Reserved { name: name.clone() });
+        }
+        if self.reject_confusables {
+            let folded = skeleton(username);
+            if let Some(name) = self.reserved_names.iter().find(|r| skeleton(r) == folded) {
+                return Err(UsernameError::ConfusableWithReserved {
+                    reserved: name.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn length_is_counted_in_bytes() {
+        let policy = UsernamePolicy {
+            allow_non_ascii: true,
+            ..UsernamePolicy::default()
+        };
+        assert_eq!(policy.check(&"a".repeat(49)), Ok(()));
+        assert_eq!(
+            policy.check(&"a".repeat(50)),
+            Err(UsernameError::TooLong { len: 50, max: 49 })
+        );
+        // 25 two-byte letters do not fit the 49 byte field
+        assert_eq!(
+            policy.check(&"é".repeat(25)),
+            Err(UsernameError::TooLong { len: 50, max: 49 })
+        );
+        // two characters but three bytes
+        assert_eq!(policy.check("éa"), Ok(()));
+    }
+
+    #[test]
+    fn first_failing_rule_is_reported() {
+        let policy = UsernamePolicy::default();
+        // markup is checked before the path characters and the charset
+        assert_eq!(
+            policy.check("<../x y>"),
+            Err(UsernameError::HtmlMarkup('<'))
+        );
+        assert_eq!(policy.check("../x y"), Err(UsernameError::PathCharacters));
+        assert_eq!(policy.check("x y"), Err(UsernameError::DisallowedChar(' ')));
+        // a single dot is allowed, two in a row are a path
+        assert_eq!(policy.check("j.doe"), Ok(()));
+        assert_eq!(policy.check("j..doe"), Err(UsernameError::PathCharacters));
+    }
+
+    #[test]
+    fn reserved_names_ignore_case_and_lookalikes() {
+        let policy = UsernamePolicy::default();
+        assert_eq!(
+            policy.check("AdMiN"),
+            Err(UsernameError::Reserved {
+                name: "admin".to_string()
+            })
+        );
+        for (name, reserved) in [("r00t", "root"), ("R_O_O_T", "root"), ("4dm1n", "admin")] {
+            assert_eq!(
+                policy.check(name),
+                Err(UsernameError::ConfusableWithReserved {
+                    reserved: reserved.to_string()
+                }),
+                "{:?}",
+                name
+            );
+        }
+        // a longer name that only contains a reserved one is fine
+        assert_eq!(policy.check("rootbeer"), Ok(()));
+
+        let lax = UsernamePolicy {
+            reject_confusables: false,
+            ..UsernamePolicy::default()
+        };
+        assert_eq!(lax.check("r00t"), Ok(()));
+    }
+
+    #[test]
+    fn non_ascii_letters_cannot_mix_scripts() {
+        let policy = UsernamePolicy {
+            allow_non_ascii: true,
+            ..UsernamePolicy::default()
+        };
+        assert_eq!(
+            UsernamePolicy::default().check("zoë"),
+            Err(UsernameError::DisallowedChar('ë'))
+        );
+        assert_eq!(policy.check("zoë"), Ok(()));
+        assert_eq!(policy.check("дмитрий"), Ok(()));
+        // Cyrillic о in an otherwise Latin name
+        assert_eq!(policy.check("rооtkit"), Err(UsernameError::MixedScript));
+        // all Cyrillic, but it folds to "system"
+        assert_eq!(
+            policy.check("ѕуѕтем"),
+            Err(UsernameError::ConfusableWithReserved {
+                reserved: "system".to_string(),
+            })
+        );
+    }
+}
@@ -0,0 +1,535 @@
+/* ------wal.rs------
+ *
+ * write ahead log for EnhancedStudentDatabase, every mutation is appended before it is applied so
+ * a crash part way through a day can be recovered from the last snapshot
+ *
+ * layout (all integers little endian), the file is just records back to back:
+ *   record:  payload length u32 | crc32 u32 (over the payload) | payload
+ *   payload: kind u8 | day i32 | fields for that kind
+ *   strings are written as length u16 | bytes, password hashes as
+ *   version u32 | iterations u32 | salt [u8; 16] | hash [u8; 32] (the plaintext is never logged)
+ *
+ * day is the day being processed when the record was written (current day + 1), so records
+ * already covered by a snapshot can be skipped. EndOfDay is written before increase_day runs and
+ * stands for everything it does (syncing signups to either backend, the daily updates and the
+ * join_databases every 5 days) since those only depend on the state before it.
+ *
+ * a day is the unit of recovery: replay stops at the last EndOfDay and the records after it are
+ * dropped, the driver re-runs that day from its input instead. so only EndOfDay is synced to disk,
+ * which also makes the records of its day durable - syncing each record would pay for records
+ * that recovery never uses.
+ * a bad record at the end of the file is a torn append and is treated as the end of the log,
+ * a bad record with more records after it is corruption and recovery refuses to continue.
+ */
+
+use crate::database_fix_full::PasswordHash;
+use crate::snapshot::crc32;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WalRecord {
+    Signup {
+        day: i32,
+        username: String,
+        email: String,
+        password_hash: PasswordHash,
+    },
+    // a successful login, this is also where the session is created
+    Login { day: i32, username: String },
+    Rename {
+        day: i32,
+        username: String,
+        new_username: String,
+    },
+    ChangePassword {
+        day: i32,
+        username: String,
+        password_hash: PasswordHash,
+    },
+    // an archived user put back, see EnhancedStudentDatabase::restore_user
+    Restore { day: i32, username: String },
+    EndOfDay { day: i32 },
+}
+
+const KIND_SIGNUP: u8 = 1;
+const KIND_LOGIN: u8 = 2;
+const KIND_RENAME: u8 = 3;
+const KIND_CHANGE_PASSWORD: u8 = 4;
+const KIND_END_OF_DAY: u8 = 5;
+const KIND_RESTORE: u8 = 6;
+
+#[derive(Debug)]
+pub enum WalError {
+    Io(std::io::Error),
+    FieldTooLong(&'static str),
+    Corrupt { record: usize },
+}
+
+impl std::fmt::Display for WalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WalError::Io(e) => write!(f, "write ahead log io error: {}", e),
+            WalError::FieldTooLong(field) => {
+                write!(f, "{} is too long for the write ahead log", field)
+            }
+            WalError::Corrupt { record } => {
+                write!(f, "write ahead log record {} is corrupt", record)
+            }
+        }
+    }
+}
+
+impl std::error::Error for WalError {}
+
+impl From<std::io::Error> for WalError {
+    fn from(e: std::io::Error) -> Self {
+        WalError::Io(e)
+    }
+}
+
+impl WalRecord {
+    pub fn day(&self) -> i32 {
+        match self {
+            WalRecord::Signup { day, .. }
+            | WalRecord::Login { day, .. }
+            | WalRecord::Rename { day, .. }
+            | WalRecord::ChangePassword { day, .. }
+            | WalRecord::Restore { day, .. }
+            | WalRecord::EndOfDay { day } => *day,
+        }
+    }
+}
+
+fn encode_str(field: &'static str, s: &str, out: &mut Vec<u8>) -> Result<(), WalError> {
+    if s.len() > u16::MAX as usize {
+        return Err(WalError::FieldTooLong(field));
+    }
+    out.extend_from_slice(&(s.len() as u16).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+fn encode_hash(hash: &PasswordHash, out: &mut Vec<u8>) {
+    out.extend_from_slice(&hash.version.to_le_bytes());
+    out.extend_from_slice(&hash.iterations.to_le_bytes());
+    out.extend_from_slice(&hash.salt);
+    out.extend_from_slice(&hash.hash);
+}
+
+fn encode_payload(record: &WalRecord) -> Result<Vec<u8>, WalError> {
+    let mut out = Vec::new();
+    match record {
+        WalRecord::Signup {
+            day,
+            username,
+            email,
+            password_hash,
+        } => {
+            out.push(KIND_SIGNUP);
+            out.extend_from_slice(&day.to_le_bytes());
+            encode_str("username", username, &mut out)?;
+            encode_str("email", email, &mut out)?;
+            encode_hash(password_hash, &mut out);
+        }
+        WalRecord::Login { day, username } => {
+            out.push(KIND_LOGIN);
+            out.extend_from_slice(&day.to_le_bytes());
+            encode_str("username", username, &mut out)?;
+        }
+        WalRecord::Rename {
+            day,
+            username,
+            new_username,
+        } => {
+            out.push(KIND_RENAME);
+            out.extend_from_slice(&day.to_le_bytes());
+            encode_str("username", username, &mut out)?;
+            encode_str("new username", new_username, &mut out)?;
+        }
+        WalRecord::ChangePassword {
+            day,
+            username,
+            password_hash,
+        } => {
+            out.push(KIND_CHANGE_PASSWORD);
+            out.extend_from_slice(&day.to_le_bytes());
+            encode_str("username", username, &mut out)?;
+            encode_hash(password_hash, &mut out);
+        }
+        WalRecord::Restore { day, username } => {
+            out.push(KIND_RESTORE);
+            out.extend_from_slice(&day.to_le_bytes());
+            encode_str("username", username, &mut out)?;
+        }
+        WalRecord::EndOfDay { day } => {
+            out.push(KIND_END_OF_DAY);
+            out.extend_from_slice(&day.to_le_bytes());
+        }
+    }
+    Ok(out)
+}
+
+pub fn encode(record: &WalRecord) -> Result<Vec<u8>, WalError> {
+    let payload = encode_payload(record)?;
+    let mut out = Vec::with_capacity(payload.len() + 8);
+    out.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    out.extend_from_slice(&crc32(&payload).to_le_bytes());
+    out.extend_from_slice(&payload);
+    Ok(out)
+}
+
+// reads pieces off the front of a payload, None means the payload was cut short
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.bytes.len() - self.pos < n {
+            return None;
+        }
+        let slice = &self.bytes[self.pos..self.pos + n];
+        self.pos += n;
+        Some(slice)
+    }
+
+    fn array<const N: usize>(&mut self) -> Option<[u8; N]> {
+        let mut arr = [0u8; N];
+        arr.copy_from_slice(self.take(N)?);
+        Some(arr)
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.array::<4>()?))
+    }
+
+    fn i32(&mut self) -> Option<i32> {
+        Some(i32::from_le_bytes(self.array::<4>()?))
+    }
+
+    fn string(&mut self) -> Option<String> {
+        let len = u16::from_le_bytes(self.array::<2>()?) as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn hash(&mut self) -> Option<PasswordHash> {
+        Some(PasswordHash {
+            version: self.u32()?,
+            iterations: self.u32()?,
+            salt: self.array()?,
+            hash: self.array()?,
+        })
+    }
+}
+
+fn decode_payload(payload: &[u8]) -> Option<WalRecord> {
+    let mut reader = Reader {
+        bytes: payload,
+        pos: 0,
+    };
+    let kind = reader.take(1)?[0];
+    let day = reader.i32()?;
+    let record = match kind {
+        KIND_SIGNUP => WalRecord::Signup {
+            day,
+            username: reader.string()?,
+            email: reader.string()?,
+            password_hash: reader.hash()?,
+        },
+        KIND_LOGIN => WalRecord::Login {
+            day,
+            username: reader.string()?,
+        },
+        KIND_RENAME => WalRecord::Rename {
+            day,
+            username: reader.string()?,
+            new_username: reader.string()?,
+        },
+        KIND_CHANGE_PASSWORD => WalRecord::ChangePassword {
+            day,
+            username: reader.string()?,
+            password_hash: reader.hash()?,
+        },
+        KIND_END_OF_DAY => WalRecord::EndOfDay { day },
+        KIND_RESTORE => WalRecord::Restore {
+            day,
+            username: reader.string()?,
+        },
+        _ => return None,
+    };
+    if reader.pos != payload.len() {
+        return None;
+    }
+    Some(record)
+}
+
+/// Decode every record, a torn last record is dropped
+pub fn decode(bytes: &[u8]) -> Result<Vec<WalRecord>, WalError> {
+    let mut records = Vec::new();
+    let mut pos = 0;
+    while bytes.len() - pos >= 8 {
+        let len = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap());
+        pos += 8;
+        if bytes.len() - pos < len {
+            log::warn!("WAL: TORN RECORD AT END OF LOG, IGNORING IT");
+            break;
+        }
+        let payload = &bytes[pos..pos + len];
+        pos += len;
+        let record = if crc32(payload) == checksum {
+            decode_payload(payload)
+        } else {
+            None
+        };
+        match record {
+            Some(record) => records.push(record),
+            None if pos == bytes.len() => {
+                log::warn!("WAL: BAD RECORD AT END OF LOG, IGNORING IT");
+                break;
+            }
+            None => return Err(WalError::Corrupt { record: records.len() }),
+        }
+    }
+    Ok(records)
+}
+
+/// Read the records of a log, a missing file is an empty log
+pub fn read_file(path: &Path) -> Result<Vec<WalRecord>, WalError> {
+    match fs::read(path) {
+        Ok(bytes) => decode(&bytes),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(WalError::Io(e)),
+    }
+}
+
+/// The records replay should apply: everything after `snapshot_day` up to the last EndOfDay
+pub fn committed(records: Vec<WalRecord>, snapshot_day: i32) -> Vec<WalRecord> {
+    let mut records: Vec<WalRecord> = records
+        .into_iter()
+        .filter(|r| r.day() > snapshot_day)
+        .collect();
+    let end = records
+        .iter()
+        .rposition(|r| matches!(r, WalRecord::EndOfDay { .. }))
+        .map_or(0, |i| i + 1);
+    if end < records.len() {
+        log::warn!(
+            "WAL: DROPPING {} RECORDS OF UNFINISHED DAY {}",
+            records.len() - end,
+            records[end].day()
+        );
+    }
+    records.truncate(end);
+    records
+}
+
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Wal {
+    /// Replace the log at `path` with `records` (temp file and rename, like snapshots) and open it for appending
+    pub fn create(path: &Path, records: &[WalRecord]) -> Result<Wal, WalError> {
+        let mut bytes = Vec::new();
+        for record in records {
+            bytes.extend_from_slice(&encode(record)?);
+        }
+        let tmp = path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&bytes)?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, path)?;
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Wal {
+            path: path.to_path_buf(),
+            file,
+        })
+    }
+
+    /// Append a record before its mutation is applied, EndOfDay also waits for the day to reach the disk
+    pub fn append(&mut self, record: &WalRecord) -> Result<(), WalError> {
+        let bytes = encode(record)?;
+        self.file.write_all(&bytes)?;
+        // the records of a day are only replayed once its EndOfDay is in the log
+        if let WalRecord::EndOfDay { .. } = record {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Drop every record, called once a snapshot covering them is safely on disk
+    pub fn checkpoint(&self) -> Result<(), WalError> {
+        self.file.set_len(0)?;
+        self.file.sync_all()?;
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hash(fill: u8) -> PasswordHash {
+        PasswordHash {
+            version: 1,
+            iterations: 1000,
+            salt: [fill; 16],
+            hash: [fill; 32],
+        }
+    }
+
+    // one record of every kind, over two days
+    fn sample() -> Vec<WalRecord> {
+        vec![
+            WalRecord::Signup {
+                day: 1,
+                username: "alice".to_string(),
+                email: "alice@example.com".to_string(),
+                password_hash: hash(1),
+            },
+            WalRecord::Login {
+                day: 1,
+                username: "alice".to_string(),
+            },
+            WalRecord::EndOfDay { day: 1 },
+            WalRecord::Rename {
+                day: 2,
+                username: "alice".to_string(),
+                new_username: "alice_b".to_string(),
+            },
+            WalRecord::ChangePassword {
+                day: 2,
+                username: "alice_b".to_string(),
+                password_hash: hash(2),
+            },
+            WalRecord::Restore {
+                day: 2,
+                username: "bob".to_string(),
+            },
+            WalRecord::EndOfDay { day: 2 },
+        ]
+    }
+
+    fn encode_all(records: &[WalRecord]) -> Vec<u8> {
+        records.iter().flat_map(|r| encode(r).unwrap()).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        assert_eq!(decode(&encode_all(&sample())).unwrap(), sample());
+        assert_eq!(decode(&[]).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn torn_tail_is_dropped() {
+        let records = sample();
+        let bytes = encode_all(&records);
+        let last_len = encode(&records[records.len() - 1]).unwrap().len();
+        // every cut inside the last record, including one that leaves only part of its length
+        for cut in 1..last_len {
+            let decoded = decode(&bytes[..bytes.len() - cut]).unwrap();
+            assert_eq!(decoded, records[..records.len() - 1], "{} bytes cut", cut);
+        }
+    }
+
+    #[test]
+    fn bad_last_record_is_dropped() {
+        let records = sample();
+        let mut bytes = encode_all(&records);
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        assert_eq!(decode(&bytes).unwrap(), records[..records.len() - 1]);
+    }
+
+    #[test]
+    fn bad_record_before_the_end_is_corruption() {
+        let records = sample();
+        let mut bytes = encode_all(&records);
+        // the username of the login record
+        let offset = encode(&records[0]).unwrap().len() + 8 + 1 + 4 + 2;
+        bytes[offset] ^= 0x20;
+        assert!(matches!(
+            decode(&bytes),
+            Err(WalError::Corrupt { record: 1 })
+        ));
+
+        // an unknown kind with a valid checksum is just as corrupt
+        let payload = [42u8, 1, 0, 0, 0];
+        let mut unknown = Vec::new();
+        unknown.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        unknown.extend_from_slice(&crc32(&payload).to_le_bytes());
+        unknown.extend_from_slice(&payload);
+        unknown.extend_from_slice(&encode(&WalRecord::EndOfDay { day: 1 }).unwrap());
+        assert!(matches!(
+            decode(&unknown),
+            Err(WalError::Corrupt { record: 0 })
+        ));
+    }
+
+    #[test]
+    fn oversized_field_is_refused() {
+        let record = WalRecord::Login {
+            day: 1,
+            username: "a".repeat(u16::MAX as usize + 1),
+        };
+        assert!(matches!(
+            encode(&record),
+            Err(WalError::FieldTooLong("username"))
+        ));
+    }
+
+    #[test]
+    fn committed_skips_the_snapshot_and_the_unfinished_day() {
+        let mut records = sample();
+        records.push(WalRecord::Login {
+            day: 3,
+            username: "alice_b".to_string(),
+        });
+        for (snapshot_day, expected) in [
+            (0, &records[..7]),
+            (1, &records[3..7]),
+            (2, &records[7..7]),
+            (3, &records[8..8]),
+        ] {
+            assert_eq!(
+                committed(records.clone(), snapshot_day),
+                expected,
+                "snapshot of day {}",
+                snapshot_day
+            );
+        }
+    }
+
+    #[test]
+    fn file_round_trip() {
+        let dir = std::env::temp_dir().join(format!("wal-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("db.wal");
+        assert_eq!(read_file(&path).unwrap(), Vec::new());
+
+        let records = sample();
+        let mut wal = Wal::create(&path, &records[..3]).unwrap();
+        for record in &records[3..] {
+            wal.append(record).unwrap();
+        }
+        assert_eq!(read_file(&path).unwrap(), records);
+
+        // create replaces whatever was there
+        let wal = Wal::create(wal.path(), &records[..1]).unwrap();
+        assert_eq!(read_file(&path).unwrap(), records[..1]);
+        wal.checkpoint().unwrap();
+        assert_eq!(read_file(&path).unwrap(), Vec::new());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}